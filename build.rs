@@ -0,0 +1,15 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::env;
+
+// Generates the messages and gRPC bindings of `proto/import_kv_extpb.proto`.
+fn main() {
+    println!("cargo:rerun-if-changed=proto/import_kv_extpb.proto");
+    let out_dir = env::var("OUT_DIR").unwrap();
+    grpcio_compiler::prost_codegen::compile_protos(
+        &["proto/import_kv_extpb.proto"],
+        &["proto"],
+        &out_dir,
+    )
+    .unwrap();
+}
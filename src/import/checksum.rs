@@ -0,0 +1,121 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+const TABLE_PREFIX: u8 = b't';
+const TABLE_PREFIX_LEN: usize = 9;
+const SIGN_MASK: u64 = 0x8000_0000_0000_0000;
+
+/// Checksum of KV pairs, which is the same as the checksum computed by the
+/// checksum request of TiKV coprocessor, so that it can be compared with
+/// `ADMIN CHECKSUM TABLE` of TiDB.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Checksum {
+    /// XOR of the CRC64 of each key and value.
+    pub crc64_xor: u64,
+    pub total_kvs: u64,
+    pub total_bytes: u64,
+}
+
+impl Checksum {
+    /// Adds a KV pair, `key` is the raw key without timestamp.
+    pub fn update(&mut self, key: &[u8], value: &[u8]) {
+        let mut digest = crc64fast::Digest::new();
+        digest.write(key);
+        digest.write(value);
+        self.crc64_xor ^= digest.sum64();
+        self.total_kvs += 1;
+        self.total_bytes += (key.len() + value.len()) as u64;
+    }
+}
+
+/// EngineChecksum is the checksum of all KV pairs in an engine, and of the KV
+/// pairs of each table.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct EngineChecksum {
+    pub total: Checksum,
+    /// Checksums indexed by table ids. Keys which don't belong to a table are
+    /// only counted in the total checksum.
+    pub tables: BTreeMap<i64, Checksum>,
+}
+
+impl EngineChecksum {
+    pub fn update(&mut self, key: &[u8], value: &[u8]) {
+        self.total.update(key, value);
+        if let Some(table_id) = decode_table_id(key) {
+            self.tables.entry(table_id).or_default().update(key, value);
+        }
+    }
+}
+
+/// Decodes the table id of a TiDB key, which is prefixed by `t` and the
+/// table id encoded in the memcomparable format.
+fn decode_table_id(key: &[u8]) -> Option<i64> {
+    if key.len() < TABLE_PREFIX_LEN || key[0] != TABLE_PREFIX {
+        return None;
+    }
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&key[1..TABLE_PREFIX_LEN]);
+    Some((u64::from_be_bytes(buf) ^ SIGN_MASK) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_key(table_id: i64, suffix: &[u8]) -> Vec<u8> {
+        let mut key = vec![TABLE_PREFIX];
+        key.extend_from_slice(&((table_id as u64) ^ SIGN_MASK).to_be_bytes());
+        key.extend_from_slice(suffix);
+        key
+    }
+
+    #[test]
+    fn test_decode_table_id() {
+        for id in &[i64::MIN, -1, 0, 1, 42, i64::MAX] {
+            assert_eq!(decode_table_id(&table_key(*id, b"_r")), Some(*id));
+        }
+        assert_eq!(decode_table_id(b""), None);
+        assert_eq!(decode_table_id(b"t1234"), None);
+        assert_eq!(decode_table_id(b"m123456789"), None);
+    }
+
+    #[test]
+    fn test_engine_checksum() {
+        let mut checksum = EngineChecksum::default();
+        let pairs = vec![
+            (table_key(1, b"_r1"), b"v1".to_vec()),
+            (table_key(1, b"_r2"), b"v2".to_vec()),
+            (table_key(2, b"_r1"), b"v3".to_vec()),
+            (b"k".to_vec(), b"v".to_vec()),
+        ];
+        for (k, v) in &pairs {
+            checksum.update(k, v);
+        }
+
+        let mut total = Checksum::default();
+        for (k, v) in &pairs {
+            let mut digest = crc64fast::Digest::new();
+            digest.write(k);
+            digest.write(v);
+            total.crc64_xor ^= digest.sum64();
+        }
+        total.total_kvs = 4;
+        total.total_bytes = pairs.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum();
+        assert_eq!(checksum.total, total);
+
+        assert_eq!(checksum.tables.len(), 2);
+        assert_eq!(checksum.tables[&1].total_kvs, 2);
+        assert_eq!(checksum.tables[&2].total_kvs, 1);
+        let xor = checksum.tables.values().fold(0, |x, c| x ^ c.crc64_xor);
+        let mut other = Checksum::default();
+        other.update(b"k", b"v");
+        assert_eq!(xor ^ other.crc64_xor, total.crc64_xor);
+    }
+}
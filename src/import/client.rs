@@ -1,35 +1,49 @@
 // Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::io::Read;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Duration;
 
 use futures::future::{self, BoxFuture, FutureExt, TryFuture, TryFutureExt};
 use futures::lock::Mutex;
 use futures::stream::{self, StreamExt};
 use futures::SinkExt;
-use grpcio::{CallOption, Channel, ChannelBuilder, EnvBuilder, Environment, WriteFlags};
+use grpcio::{
+    CallOption, Channel, ChannelBuilder, CompressionAlgorithms, EnvBuilder, Environment, WriteFlags,
+};
 
 use engine_rocksdb::SequentialFile;
+use kvproto::errorpb;
 use kvproto::import_sstpb::*;
 use kvproto::kvrpcpb::*;
-use kvproto::pdpb::OperatorStatus;
+use kvproto::metapb::{Store, StoreState};
+use kvproto::pdpb::{
+    ErrorType, GetOperatorRequest, OperatorStatus, PdClient as PdStub, ScanRegionsRequest,
+};
 use kvproto::tikvpb::TikvClient;
 
 use collections::{HashMap, HashMapEntry};
-use pd_client::{Config as PdConfig, Error as PdError, PdClient, RegionInfo, RpcClient};
+use pd_client::{Config as PdConfig, PdClient, RegionInfo, RpcClient};
 use security::SecurityManager;
+use tikv::server::config::GrpcCompressionType;
 use txn_types::Key;
 
 use super::common::*;
-use super::{Error, Result};
+use super::config::GrpcConfig;
+use super::region_cache::RegionCache;
+use super::{Config, Error, Result};
 
 pub trait ImportClient: Send + Sync + Clone + 'static {
     fn get_region<'a>(&'a self, _: &'a [u8]) -> BoxFuture<'a, Result<RegionInfo>> {
         unimplemented!()
     }
 
-    fn split_region(&self, _: &RegionInfo, _: &[u8]) -> BoxFuture<'_, Result<SplitRegionResponse>> {
+    /// Splits the region at all the split keys in one request.
+    fn split_regions(
+        &self,
+        _: &RegionInfo,
+        _: &[Vec<u8>],
+    ) -> BoxFuture<'_, Result<SplitRegionResponse>> {
         unimplemented!()
     }
 
@@ -37,6 +51,13 @@ pub trait ImportClient: Send + Sync + Clone + 'static {
         unimplemented!()
     }
 
+    fn scatter_regions(&self, regions: &[RegionInfo]) -> Result<()> {
+        for region in regions {
+            self.scatter_region(region)?;
+        }
+        Ok(())
+    }
+
     fn upload_sst(&self, _: u64, _: UploadStream) -> BoxFuture<'_, Result<UploadResponse>> {
         unimplemented!()
     }
@@ -49,7 +70,7 @@ pub trait ImportClient: Send + Sync + Clone + 'static {
         unimplemented!()
     }
 
-    fn is_scatter_region_finished(&self, _: u64) -> Result<bool> {
+    fn is_scatter_region_finished(&self, _: u64) -> BoxFuture<'_, Result<bool>> {
         unimplemented!()
     }
 
@@ -58,29 +79,53 @@ pub trait ImportClient: Send + Sync + Clone + 'static {
     }
 }
 
-fn grpc_timeout(secs: u64) -> CallOption {
+// The max number of regions loaded from PD in one scan.
+const REGION_SCAN_LIMIT: i32 = 128;
+const REGION_SCAN_TIMEOUT_SECS: u64 = 3;
+// The max number of regions cached by one client.
+const REGION_CACHE_CAPACITY: usize = 65536;
+const GET_OPERATOR_TIMEOUT_SECS: u64 = 3;
+
+const ENGINE_LABEL_KEY: &str = "engine";
+const TIFLASH_ENGINE: &str = "tiflash";
+
+fn grpc_timeout(timeout: Duration) -> CallOption {
     let write_flags = WriteFlags::default().buffer_hint(true);
     CallOption::default()
-        .timeout(Duration::from_secs(secs))
+        .timeout(timeout)
         .write_flags(write_flags)
 }
 
+fn compression_algorithm(compression_type: &GrpcCompressionType) -> CompressionAlgorithms {
+    match compression_type {
+        GrpcCompressionType::None => CompressionAlgorithms::GRPC_COMPRESS_NONE,
+        GrpcCompressionType::Deflate => CompressionAlgorithms::GRPC_COMPRESS_DEFLATE,
+        GrpcCompressionType::Gzip => CompressionAlgorithms::GRPC_COMPRESS_GZIP,
+    }
+}
+
 pub struct Client {
     pd: Arc<RpcClient>,
     env: Arc<Environment>,
-    channels: Mutex<HashMap<u64, Channel>>,
+    // Channels of stores, shared by all clones of the client.
+    channels: Arc<Mutex<HashMap<u64, Channel>>>,
+    // Channel of the PD leader and its address, rebuilt once the leader
+    // changes.
+    pd_channel: Arc<StdMutex<Option<(String, Channel)>>>,
+    // Regions loaded from PD, shared by all clones of the client.
+    region_cache: Arc<StdMutex<RegionCache>>,
     min_available_ratio: f64,
+    grpc: GrpcConfig,
     security_mgr: Arc<SecurityManager>,
 }
 
 impl Client {
     pub async fn new(
-        pd_addr: &str,
+        pd_cfg: &PdConfig,
         cq_count: usize,
-        min_available_ratio: f64,
+        cfg: &Config,
         security_mgr: Arc<SecurityManager>,
     ) -> Result<Client> {
-        let cfg = PdConfig::new(vec![pd_addr.to_owned()]);
         let env = Arc::new(
             EnvBuilder::new()
                 .name_prefix("import-client")
@@ -88,12 +133,15 @@ impl Client {
                 .build(),
         );
         let rpc_client =
-            RpcClient::new_async(&cfg, Some(env.clone()), security_mgr.clone()).await?;
+            RpcClient::new_async(pd_cfg, Some(env.clone()), security_mgr.clone()).await?;
         Ok(Client {
             pd: Arc::new(rpc_client),
             env,
-            channels: Mutex::new(HashMap::default()),
-            min_available_ratio,
+            channels: Arc::new(Mutex::new(HashMap::default())),
+            pd_channel: Arc::default(),
+            region_cache: Arc::new(StdMutex::new(RegionCache::new(REGION_CACHE_CAPACITY))),
+            min_available_ratio: cfg.min_available_ratio,
+            grpc: cfg.grpc.clone(),
             security_mgr,
         })
     }
@@ -104,7 +152,14 @@ impl Client {
             HashMapEntry::Occupied(e) => Ok(e.get().clone()),
             HashMapEntry::Vacant(e) => {
                 let store = self.pd.get_store_async(store_id).await?;
-                let builder = ChannelBuilder::new(self.env.clone());
+                let builder = ChannelBuilder::new(self.env.clone())
+                    .keepalive_time(self.grpc.keepalive_time.0)
+                    .keepalive_timeout(self.grpc.keepalive_timeout.0)
+                    .max_send_message_len(self.grpc.max_send_msg_len.0 as i32)
+                    .max_receive_message_len(self.grpc.max_recv_msg_len.0 as i32)
+                    .default_compression_algorithm(compression_algorithm(
+                        &self.grpc.compression_type,
+                    ));
                 let tar_addr = if !store.get_peer_address().is_empty() {
                     store.get_peer_address()
                 } else {
@@ -123,63 +178,158 @@ impl Client {
         R::Error: Into<Error>,
     {
         let ch = self.resolve(store_id).await?;
-        let res = action(ch).into_future().await;
-        if res.is_err() {
+        let res = action(ch).into_future().await.map_err(Into::into);
+        if let Err(e) = &res {
+            // The channel may be broken or the store address may have
+            // changed, resolve the store from PD again on the next request.
+            debug!("drop store channel"; "store" => %store_id, "err" => %e);
             self.channels.lock().await.remove(&store_id);
         }
-        res.map_err(Into::into)
+        res
     }
 
-    pub async fn switch_cluster(&self, req: &SwitchModeRequest) -> Result<()> {
-        let mut futures = Vec::new();
+    /// Returns a client of the PD leader, for requests which have no async
+    /// version in `PdClient`.
+    fn pd_stub(&self) -> Result<PdStub> {
+        let leader = self.pd.get_leader();
+        let url = match leader.get_client_urls().first() {
+            Some(url) => url,
+            None => return Err(Error::InvalidPdAddr(format!("{:?}", leader))),
+        };
+        let addr = url
+            .trim_start_matches("http://")
+            .trim_start_matches("https://");
+        let mut pd_channel = self.pd_channel.lock().unwrap();
+        if let Some((cached_addr, ch)) = &*pd_channel {
+            if cached_addr == addr {
+                return Ok(PdStub::new(ch.clone()));
+            }
+        }
+        let builder = ChannelBuilder::new(self.env.clone());
+        let ch = self.security_mgr.connect(builder, addr);
+        *pd_channel = Some((addr.to_owned(), ch.clone()));
+        Ok(PdStub::new(ch))
+    }
+
+    /// Loads at most `limit` regions starting from the region containing
+    /// `key` from PD.
+    async fn scan_regions(&self, key: &[u8], limit: i32) -> Result<Vec<RegionInfo>> {
+        let client = self.pd_stub()?;
+        let mut req = ScanRegionsRequest::default();
+        req.mut_header().set_cluster_id(self.pd.get_cluster_id()?);
+        req.set_start_key(key.to_owned());
+        req.set_limit(limit);
+        let mut resp = client
+            .scan_regions_async_opt(
+                &req,
+                grpc_timeout(Duration::from_secs(REGION_SCAN_TIMEOUT_SECS)),
+            )?
+            .await?;
+        if resp.get_header().has_error() {
+            return Err(Error::PdResponse(resp.mut_header().take_error()));
+        }
+
+        let leaders = resp.take_leaders();
+        let regions = resp
+            .take_region_metas()
+            .into_iter()
+            .enumerate()
+            .map(|(i, region)| {
+                // The leader is unknown if its id is 0.
+                let leader = leaders.get(i).filter(|p| p.get_id() != 0).cloned();
+                RegionInfo::new(region, leader)
+            })
+            .collect();
+        Ok(regions)
+    }
+
+    /// Invalidates the cached region on errors which mean it is stale.
+    fn on_region_error(&self, region_id: u64, err: &errorpb::Error) {
+        if err.has_not_leader() || err.has_epoch_not_match() || err.has_region_not_found() {
+            self.region_cache.lock().unwrap().invalidate(region_id);
+        }
+    }
+
+    pub fn cluster_id(&self) -> Result<u64> {
+        Ok(self.pd.get_cluster_id()?)
+    }
+
+    /// Returns stores which switch mode and compact requests are sent to.
+    fn cluster_stores(&self) -> Result<Vec<Store>> {
         // Exclude tombstone stores.
-        for store in self.pd.get_all_stores(true)? {
-            let ch = match self.resolve(store.get_id()).await {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("get store channel failed"; "store" => ?store, "err" => %e);
-                    continue;
-                }
-            };
-            let client = ImportSstClient::new(ch);
-            let future = match client.switch_mode_async(req) {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("switch mode failed"; "store" => ?store, "err" => %e);
-                    continue;
-                }
-            };
-            futures.push(future);
+        let mut stores = self.pd.get_all_stores(true)?;
+        stores.retain(|store| {
+            let skip = !is_tikv_store(store) || store.get_state() != StoreState::Up;
+            if skip {
+                info!("skip store"; "store" => %store.get_id(), "address" => store.get_address(), "state" => ?store.get_state(), "labels" => ?store.get_labels());
+            }
+            !skip
+        });
+        Ok(stores)
+    }
+
+    pub async fn switch_cluster(&self, req: &SwitchModeRequest, strict: bool) -> Result<()> {
+        let mut futures = Vec::new();
+        for store in self.cluster_stores()? {
+            let store_id = store.get_id();
+            let future = self.with_resolve(store_id, |ch| async move {
+                let client = ImportSstClient::new(ch);
+                client.switch_mode_async(req)?.await
+            });
+            futures.push(future.map(move |res| (store_id, res)));
         }
 
-        future::try_join_all(futures).await?;
-        Ok(())
+        let results = future::join_all(futures).await;
+        check_store_results("switch mode", results, strict)
     }
 
-    pub async fn compact_cluster(&self, req: &CompactRequest) -> Result<()> {
+    pub async fn compact_cluster(&self, req: &CompactRequest, strict: bool) -> Result<()> {
         let mut futures = Vec::new();
-        // Exclude tombstone stores.
-        for store in self.pd.get_all_stores(true)? {
-            let ch = match self.resolve(store.get_id()).await {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("get store channel failed"; "store" => ?store, "err" => %e);
-                    continue;
-                }
-            };
-            let client = ImportSstClient::new(ch);
-            let future = match client.compact_async(req) {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("compact failed"; "store" => ?store, "err" => %e);
-                    continue;
-                }
-            };
-            futures.push(future);
+        for store in self.cluster_stores()? {
+            let store_id = store.get_id();
+            let future = self.with_resolve(store_id, |ch| async move {
+                let client = ImportSstClient::new(ch);
+                client.compact_async(req)?.await
+            });
+            futures.push(future.map(move |res| (store_id, res)));
         }
 
-        future::try_join_all(futures).await?;
-        Ok(())
+        let results = future::join_all(futures).await;
+        check_store_results("compact", results, strict)
+    }
+}
+
+/// Returns false for TiFlash stores.
+fn is_tikv_store(store: &Store) -> bool {
+    !store
+        .get_labels()
+        .iter()
+        .any(|l| l.get_key() == ENGINE_LABEL_KEY && l.get_value() == TIFLASH_ENGINE)
+}
+
+/// Logs the result of each store. In strict mode, it fails if any store
+/// failed, otherwise, it only fails if all stores failed.
+fn check_store_results<T>(
+    action: &str,
+    results: Vec<(u64, Result<T>)>,
+    strict: bool,
+) -> Result<()> {
+    let num_stores = results.len();
+    let mut num_failed = 0;
+    let mut last_err = None;
+    for (store_id, res) in results {
+        match res {
+            Ok(_) => info!("store request completed"; "action" => action, "store" => %store_id),
+            Err(e) => {
+                error!("store request failed"; "action" => action, "store" => %store_id, "err" => %e);
+                num_failed += 1;
+                last_err = Some(e);
+            }
+        }
+    }
+    match last_err {
+        Some(e) if strict || num_failed == num_stores => Err(e),
+        _ => Ok(()),
     }
 }
 
@@ -188,8 +338,11 @@ impl Clone for Client {
         Client {
             pd: Arc::clone(&self.pd),
             env: Arc::clone(&self.env),
-            channels: Mutex::new(HashMap::default()),
+            channels: Arc::clone(&self.channels),
+            pd_channel: Arc::clone(&self.pd_channel),
+            region_cache: Arc::clone(&self.region_cache),
             min_available_ratio: self.min_available_ratio,
+            grpc: self.grpc.clone(),
             security_mgr: self.security_mgr.clone(),
         }
     }
@@ -198,33 +351,74 @@ impl Clone for Client {
 impl ImportClient for Client {
     fn get_region<'a>(&'a self, key: &'a [u8]) -> BoxFuture<'a, Result<RegionInfo>> {
         async move {
-            self.pd
-                .get_region_info_async(key)
-                .await
-                .map_err(Error::from)
+            let cached = self.region_cache.lock().unwrap().get(key);
+            if let Some(region) = cached {
+                return Ok(region);
+            }
+
+            // Load the following regions too, so that looking up the next
+            // keys doesn't need to ask PD.
+            match self.scan_regions(key, REGION_SCAN_LIMIT).await {
+                Ok(regions) => {
+                    let mut cache = self.region_cache.lock().unwrap();
+                    for region in regions {
+                        cache.insert(region);
+                    }
+                    if let Some(region) = cache.get(key) {
+                        return Ok(region);
+                    }
+                }
+                Err(e) => warn!("scan regions failed"; "err" => %e),
+            }
+
+            let region = self.pd.get_region_info_async(key).await?;
+            self.region_cache.lock().unwrap().insert(region.clone());
+            Ok(region)
         }
         .boxed()
     }
 
-    fn split_region(
+    fn split_regions(
         &self,
         region: &RegionInfo,
-        split_key: &[u8],
+        split_keys: &[Vec<u8>],
     ) -> BoxFuture<'_, Result<SplitRegionResponse>> {
         let ctx = new_context(region);
+        let region_id = ctx.get_region_id();
         let store_id = ctx.get_peer().get_store_id();
 
         let mut req = SplitRegionRequest::default();
         req.set_context(ctx);
-        match Key::from_encoded_slice(split_key).into_raw() {
-            Ok(key) => req.set_split_key(key),
-            Err(e) => return future::err(e.into()).boxed(),
-        };
+        for split_key in split_keys {
+            match Key::from_encoded_slice(split_key).into_raw() {
+                Ok(key) => req.mut_split_keys().push(key),
+                Err(e) => return future::err(e.into()).boxed(),
+            };
+        }
 
-        self.with_resolve(store_id, |ch| async move {
-            let client = TikvClient::new(ch);
-            client.split_region_async_opt(&req, grpc_timeout(3))?.await
-        })
+        let timeout = self.grpc.split_timeout.0;
+        async move {
+            let resp = self
+                .with_resolve(store_id, |ch| async move {
+                    let client = TikvClient::new(ch);
+                    client
+                        .split_region_async_opt(&req, grpc_timeout(timeout))?
+                        .await
+                })
+                .await?;
+            if resp.has_region_error() {
+                self.on_region_error(region_id, resp.get_region_error());
+            } else {
+                // The leaders of the new regions are on the same store as
+                // the leader of the origin region.
+                let mut cache = self.region_cache.lock().unwrap();
+                for region in resp.get_regions() {
+                    let leader = find_region_peer(region, store_id);
+                    cache.insert(RegionInfo::new(region.clone(), leader));
+                }
+            }
+            Ok(resp)
+        }
         .boxed()
     }
 
@@ -237,9 +431,11 @@ impl ImportClient for Client {
         store_id: u64,
         req: UploadStream,
     ) -> BoxFuture<'_, Result<UploadResponse>> {
+        let size = req.meta.as_ref().map_or(0, |m| m.get_length());
+        let timeout = self.grpc.upload_timeout(size);
         self.with_resolve(store_id, |ch| async move {
             let client = ImportSstClient::new(ch);
-            let (tx, rx) = client.upload_opt(grpc_timeout(30))?;
+            let (tx, rx) = client.upload_opt(grpc_timeout(timeout))?;
             stream::iter(req)
                 .forward(tx.sink_map_err(Error::from))
                 .await?;
@@ -253,10 +449,20 @@ impl ImportClient for Client {
         store_id: u64,
         req: IngestRequest,
     ) -> BoxFuture<'_, Result<IngestResponse>> {
-        self.with_resolve(store_id, |ch| async move {
-            let client = ImportSstClient::new(ch);
-            client.ingest_async_opt(&req, grpc_timeout(30))?.await
-        })
+        let region_id = req.get_context().get_region_id();
+        let timeout = self.grpc.ingest_timeout.0;
+        async move {
+            let resp = self
+                .with_resolve(store_id, |ch| async move {
+                    let client = ImportSstClient::new(ch);
+                    client.ingest_async_opt(&req, grpc_timeout(timeout))?.await
+                })
+                .await?;
+            if resp.has_error() {
+                self.on_region_error(region_id, resp.get_error());
+            }
+            Ok(resp)
+        }
         .boxed()
     }
 
@@ -264,19 +470,39 @@ impl ImportClient for Client {
         async move { Ok(self.pd.get_region_by_id(id).await?.is_some()) }.boxed()
     }
 
-    fn is_scatter_region_finished(&self, region_id: u64) -> Result<bool> {
-        match self.pd.get_operator(region_id) {
-            Ok(resp) => {
-                // If the current operator of region is not `scatter-region`, we could assume
-                // that `scatter-operator` has finished or timeout.
-                Ok(resp.desc != b"scatter-region" || resp.get_status() != OperatorStatus::Running)
-            }
-            Err(PdError::RegionNotFound(_)) => Ok(true), // heartbeat may not send to PD
-            Err(err) => {
-                error!("check scatter region operator result"; "region_id" => %region_id, "err" => %err);
-                Err(Error::from(err))
+    fn is_scatter_region_finished(&self, region_id: u64) -> BoxFuture<'_, Result<bool>> {
+        async move {
+            let client = self.pd_stub()?;
+            let mut req = GetOperatorRequest::default();
+            req.mut_header().set_cluster_id(self.pd.get_cluster_id()?);
+            req.set_region_id(region_id);
+            let res = client
+                .get_operator_async_opt(
+                    &req,
+                    grpc_timeout(Duration::from_secs(GET_OPERATOR_TIMEOUT_SECS)),
+                )?
+                .await;
+            let mut resp = match res {
+                Ok(resp) => resp,
+                Err(err) => {
+                    error!("check scatter region operator result"; "region_id" => %region_id, "err" => %err);
+                    return Err(Error::from(err));
+                }
+            };
+            if resp.get_header().has_error() {
+                let err = resp.mut_header().take_error();
+                // Heartbeat may not send to PD.
+                if err.get_type() == ErrorType::RegionNotFound {
+                    return Ok(true);
+                }
+                error!("check scatter region operator result"; "region_id" => %region_id, "err" => ?err);
+                return Err(Error::PdResponse(err));
             }
+            // If the current operator of region is not `scatter-region`, we could assume
+            // that `scatter-operator` has finished or timeout.
+            Ok(resp.desc != b"scatter-region" || resp.get_status() != OperatorStatus::Running)
         }
+        .boxed()
     }
 
     fn is_space_enough(&self, store_id: u64, size: u64) -> BoxFuture<'_, Result<bool>> {
@@ -341,6 +567,7 @@ impl<R: Read> Iterator for UploadStream<R> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use kvproto::metapb::StoreLabel;
     use rand::RngCore;
 
     #[test]
@@ -371,4 +598,32 @@ mod tests {
         }
         assert_eq!(buf, data);
     }
+
+    #[test]
+    fn test_is_tikv_store() {
+        let mut store = Store::default();
+        assert!(is_tikv_store(&store));
+
+        let mut label = StoreLabel::default();
+        label.set_key("zone".to_owned());
+        label.set_value("z1".to_owned());
+        store.mut_labels().push(label);
+        assert!(is_tikv_store(&store));
+
+        let mut label = StoreLabel::default();
+        label.set_key(ENGINE_LABEL_KEY.to_owned());
+        label.set_value(TIFLASH_ENGINE.to_owned());
+        store.mut_labels().push(label);
+        assert!(!is_tikv_store(&store));
+    }
+
+    #[test]
+    fn test_check_store_results() {
+        let err = || Err(Error::InvalidChunk);
+        assert!(check_store_results::<()>("test", vec![], true).is_ok());
+        assert!(check_store_results("test", vec![(1, Ok(())), (2, Ok(()))], true).is_ok());
+        assert!(check_store_results("test", vec![(1, Ok(())), (2, err())], true).is_err());
+        assert!(check_store_results("test", vec![(1, Ok(())), (2, err())], false).is_ok());
+        assert!(check_store_results::<()>("test", vec![(1, err()), (2, err())], false).is_err());
+    }
 }
@@ -3,12 +3,16 @@
 use std::error::Error;
 use std::path::Path;
 use std::result::Result;
+use std::time::Duration;
 
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 use engine_rocks::raw::DBCompressionType;
+use pd_client::Config as PdConfig;
 use security::SecurityConfig;
 use tikv::config::{log_level_serde, DbConfig, MetricConfig};
+use tikv::server::config::GrpcCompressionType;
 use tikv_util::config::{ReadableDuration, ReadableSize};
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
@@ -27,6 +31,8 @@ pub struct TiKvConfig {
     pub rocksdb: DbConfig,
     pub security: SecurityConfig,
     pub import: Config,
+    /// Default PD endpoints, used by requests which don't specify any.
+    pub pd: PdConfig,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
@@ -44,14 +50,25 @@ pub struct StorageConfig {
 pub struct Config {
     pub import_dir: String,
     pub num_threads: usize,
+    pub num_scan_threads: usize,
     pub num_import_jobs: usize,
     pub num_import_sst_jobs: usize,
     pub max_prepare_duration: ReadableDuration,
     pub region_split_size: ReadableSize,
+    pub max_split_keys_per_request: usize,
+    pub sst_memory_budget: ReadableSize,
+    pub detect_duplicate_keys: bool,
     pub stream_channel_window: usize,
     pub max_open_engines: usize,
     pub upload_speed_limit: ReadableSize,
+    pub store_upload_speed_limit: ReadableSize,
+    pub max_store_concurrent_uploads: usize,
+    pub max_store_concurrent_ingests: usize,
     pub min_available_ratio: f64,
+    pub strict_cluster_request: bool,
+    pub import_mode_renew_interval: ReadableDuration,
+    pub retry: RetryConfig,
+    pub grpc: GrpcConfig,
 }
 
 impl Default for Config {
@@ -59,14 +76,25 @@ impl Default for Config {
         Config {
             import_dir: "/tmp/tikv/import".to_owned(),
             num_threads: 16,
+            num_scan_threads: 2,
             num_import_jobs: 24,
             num_import_sst_jobs: 2, // this field is useless, kept just to satisfy `deny_unknown_fields`
             max_prepare_duration: ReadableDuration::minutes(5),
             region_split_size: ReadableSize::mb(512),
+            max_split_keys_per_request: 1024,
+            sst_memory_budget: ReadableSize::gb(2),
+            detect_duplicate_keys: false,
             stream_channel_window: 128,
             max_open_engines: 8,
             upload_speed_limit: ReadableSize::mb(512),
+            store_upload_speed_limit: ReadableSize(0),
+            max_store_concurrent_uploads: 16,
+            max_store_concurrent_ingests: 8,
             min_available_ratio: 0.05,
+            strict_cluster_request: false,
+            import_mode_renew_interval: ReadableDuration::minutes(1),
+            retry: RetryConfig::default(),
+            grpc: GrpcConfig::default(),
         }
     }
 }
@@ -76,6 +104,9 @@ impl Config {
         if self.num_threads == 0 {
             return Err("import.num_threads can not be 0".into());
         }
+        if self.num_scan_threads == 0 {
+            return Err("import.num_scan_threads can not be 0".into());
+        }
         if self.num_import_jobs == 0 {
             return Err("import.num_import_jobs can not be 0".into());
         }
@@ -85,6 +116,9 @@ impl Config {
         if self.region_split_size.0 == 0 {
             return Err("import.region_split_size can not be 0".into());
         }
+        if self.max_split_keys_per_request == 0 {
+            return Err("import.max_split_keys_per_request can not be 0".into());
+        }
         if self.stream_channel_window == 0 {
             return Err("import.stream_channel_window can not be 0".into());
         }
@@ -94,11 +128,203 @@ impl Config {
         if self.upload_speed_limit.0 == 0 {
             return Err("import.upload_speed_limit cannot be 0".into());
         }
+        if self.max_store_concurrent_uploads == 0 {
+            return Err("import.max_store_concurrent_uploads can not be 0".into());
+        }
+        if self.max_store_concurrent_ingests == 0 {
+            return Err("import.max_store_concurrent_ingests can not be 0".into());
+        }
         if self.min_available_ratio < 0.0 {
             return Err("import.min_available_ratio can not less than 0.02".into());
         }
+        self.retry.validate()?;
+        self.grpc.validate()?;
+        Ok(())
+    }
+}
+
+/// RetryConfig controls how import and prepare jobs retry failed requests.
+///
+/// Each retry waits for an exponential back-off starting from the base
+/// back-off and capped at the max back-off. The default back-offs of import
+/// and prepare are fixed intervals. The back-off is randomized by
+/// `jitter`, e.g. 0.1 means within 90% ~ 110% of the computed value.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct RetryConfig {
+    pub max_retry_times: u64,
+    pub base_backoff: ReadableDuration,
+    pub max_backoff: ReadableDuration,
+    pub jitter: f64,
+    pub store_unavailable_wait_interval: ReadableDuration,
+    pub prepare_max_retry_times: u64,
+    pub prepare_base_backoff: ReadableDuration,
+    pub prepare_max_backoff: ReadableDuration,
+    pub split_wait_max_retry_times: u64,
+    pub split_wait_base_backoff: ReadableDuration,
+    pub split_wait_max_backoff: ReadableDuration,
+    pub scatter_wait_max_retry_times: u64,
+    pub scatter_wait_base_backoff: ReadableDuration,
+    pub scatter_wait_max_backoff: ReadableDuration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_retry_times: 5,
+            base_backoff: ReadableDuration::secs(3),
+            max_backoff: ReadableDuration::secs(3),
+            jitter: 0.0,
+            store_unavailable_wait_interval: ReadableDuration::secs(20),
+            prepare_max_retry_times: 3,
+            prepare_base_backoff: ReadableDuration::secs(1),
+            prepare_max_backoff: ReadableDuration::secs(1),
+            split_wait_max_retry_times: 64,
+            split_wait_base_backoff: ReadableDuration::millis(16),
+            split_wait_max_backoff: ReadableDuration::secs(1),
+            scatter_wait_max_retry_times: 128,
+            scatter_wait_base_backoff: ReadableDuration::millis(100),
+            scatter_wait_max_backoff: ReadableDuration::secs(5),
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.max_retry_times == 0 {
+            return Err("import.retry.max_retry_times can not be 0".into());
+        }
+        if self.prepare_max_retry_times == 0 {
+            return Err("import.retry.prepare_max_retry_times can not be 0".into());
+        }
+        if self.split_wait_max_retry_times == 0 {
+            return Err("import.retry.split_wait_max_retry_times can not be 0".into());
+        }
+        if self.scatter_wait_max_retry_times == 0 {
+            return Err("import.retry.scatter_wait_max_retry_times can not be 0".into());
+        }
+        if self.base_backoff.0 > self.max_backoff.0
+            || self.prepare_base_backoff.0 > self.prepare_max_backoff.0
+            || self.split_wait_base_backoff.0 > self.split_wait_max_backoff.0
+            || self.scatter_wait_base_backoff.0 > self.scatter_wait_max_backoff.0
+        {
+            return Err("import.retry base backoff can not be greater than max backoff".into());
+        }
+        if self.jitter < 0.0 || self.jitter >= 1.0 {
+            return Err("import.retry.jitter must be in [0, 1)".into());
+        }
+        Ok(())
+    }
+
+    /// Returns the back-off before the `retry`-th (starting from 1) retry.
+    pub fn backoff(&self, retry: u64) -> Duration {
+        self.exponential_backoff(self.base_backoff.0, self.max_backoff.0, retry)
+    }
+
+    /// Returns the back-off before the `retry`-th (starting from 1) retry of
+    /// preparing a region.
+    pub fn prepare_backoff(&self, retry: u64) -> Duration {
+        self.exponential_backoff(
+            self.prepare_base_backoff.0,
+            self.prepare_max_backoff.0,
+            retry,
+        )
+    }
+
+    /// Returns the back-off before the `retry`-th (starting from 1) check of
+    /// whether a split region is known by PD.
+    pub fn split_wait_backoff(&self, retry: u64) -> Duration {
+        self.exponential_backoff(
+            self.split_wait_base_backoff.0,
+            self.split_wait_max_backoff.0,
+            retry,
+        )
+    }
+
+    /// Returns the back-off before the `retry`-th (starting from 1) check of
+    /// whether regions are scattered.
+    pub fn scatter_wait_backoff(&self, retry: u64) -> Duration {
+        self.exponential_backoff(
+            self.scatter_wait_base_backoff.0,
+            self.scatter_wait_max_backoff.0,
+            retry,
+        )
+    }
+
+    fn exponential_backoff(&self, base: Duration, max: Duration, retry: u64) -> Duration {
+        let shift = retry.saturating_sub(1).min(31) as u32;
+        let backoff = base.checked_mul(1 << shift).unwrap_or(max).min(max);
+        if self.jitter <= 0.0 {
+            return backoff;
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - self.jitter, 1.0 + self.jitter);
+        backoff.mul_f64(factor)
+    }
+}
+
+/// GrpcConfig controls the gRPC connections to TiKV stores.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "kebab-case")]
+pub struct GrpcConfig {
+    pub split_timeout: ReadableDuration,
+    pub ingest_timeout: ReadableDuration,
+    /// The base timeout of uploading an SST, it grows with the SST size
+    /// according to `upload_min_speed`.
+    pub upload_timeout: ReadableDuration,
+    /// 0 means the upload timeout doesn't grow with the SST size.
+    pub upload_min_speed: ReadableSize,
+    pub keepalive_time: ReadableDuration,
+    pub keepalive_timeout: ReadableDuration,
+    pub max_send_msg_len: ReadableSize,
+    pub max_recv_msg_len: ReadableSize,
+    pub compression_type: GrpcCompressionType,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> GrpcConfig {
+        GrpcConfig {
+            split_timeout: ReadableDuration::secs(3),
+            ingest_timeout: ReadableDuration::secs(30),
+            upload_timeout: ReadableDuration::secs(30),
+            upload_min_speed: ReadableSize::mb(1),
+            keepalive_time: ReadableDuration::secs(10),
+            keepalive_timeout: ReadableDuration::secs(3),
+            max_send_msg_len: ReadableSize::mb(10),
+            max_recv_msg_len: ReadableSize::mb(10),
+            compression_type: GrpcCompressionType::None,
+        }
+    }
+}
+
+impl GrpcConfig {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.split_timeout.0 == Duration::from_secs(0)
+            || self.ingest_timeout.0 == Duration::from_secs(0)
+            || self.upload_timeout.0 == Duration::from_secs(0)
+        {
+            return Err("import.grpc timeouts can not be 0".into());
+        }
+        if self.max_send_msg_len.0 == 0 || self.max_send_msg_len.0 > i32::MAX as u64 {
+            return Err("import.grpc.max_send_msg_len must be in (0, 2GB)".into());
+        }
+        if self.max_recv_msg_len.0 == 0 || self.max_recv_msg_len.0 > i32::MAX as u64 {
+            return Err("import.grpc.max_recv_msg_len must be in (0, 2GB)".into());
+        }
         Ok(())
     }
+
+    /// Returns the timeout of uploading an SST of `size` bytes.
+    pub fn upload_timeout(&self, size: u64) -> Duration {
+        let speed = self.upload_min_speed.0;
+        if speed == 0 {
+            return self.upload_timeout.0;
+        }
+        self.upload_timeout.0 + Duration::from_secs_f64(size as f64 / speed as f64)
+    }
 }
 
 impl Default for TiKvConfig {
@@ -131,6 +357,7 @@ impl Default for TiKvConfig {
             rocksdb,
             security: SecurityConfig::default(),
             import: Config::default(),
+            pd: PdConfig::default(),
             storage: StorageConfig::default(),
         }
     }
@@ -181,4 +408,70 @@ mod test {
         let res = toml::from_str::<TiKvConfig>("not-log-level = 'info'\n");
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_retry_config() {
+        let mut cfg = RetryConfig::default();
+        cfg.validate().unwrap();
+        assert_eq!(cfg.backoff(1), Duration::from_secs(3));
+        assert_eq!(cfg.backoff(100), Duration::from_secs(3));
+        assert_eq!(cfg.prepare_backoff(1), Duration::from_secs(1));
+        assert_eq!(cfg.prepare_backoff(100), Duration::from_secs(1));
+        assert_eq!(cfg.split_wait_backoff(1), Duration::from_millis(16));
+        assert_eq!(cfg.split_wait_backoff(100), Duration::from_secs(1));
+        assert_eq!(cfg.scatter_wait_backoff(2), Duration::from_millis(200));
+
+        cfg.base_backoff = ReadableDuration::secs(1);
+        cfg.validate().unwrap();
+        assert_eq!(cfg.backoff(1), Duration::from_secs(1));
+        assert_eq!(cfg.backoff(2), Duration::from_secs(2));
+        assert_eq!(cfg.backoff(3), Duration::from_secs(3));
+        assert_eq!(cfg.backoff(100), Duration::from_secs(3));
+
+        cfg.jitter = 0.5;
+        cfg.validate().unwrap();
+        for retry in 1..10 {
+            let backoff = cfg.backoff(retry);
+            assert!(backoff >= Duration::from_millis(500), "{:?}", backoff);
+            assert!(backoff <= Duration::from_millis(4500), "{:?}", backoff);
+        }
+
+        cfg.jitter = 1.0;
+        assert!(cfg.validate().is_err());
+        cfg.jitter = 0.0;
+        cfg.base_backoff = ReadableDuration::secs(10);
+        assert!(cfg.validate().is_err());
+        cfg.base_backoff = ReadableDuration::secs(1);
+        cfg.prepare_max_retry_times = 0;
+        assert!(cfg.validate().is_err());
+
+        let res = toml::from_str::<TiKvConfig>("[import.retry]\nmax-retry-times = 10\n").unwrap();
+        assert_eq!(res.import.retry.max_retry_times, 10);
+    }
+
+    #[test]
+    fn test_grpc_config() {
+        let mut cfg = GrpcConfig::default();
+        cfg.validate().unwrap();
+        assert_eq!(cfg.upload_timeout(0), Duration::from_secs(30));
+        assert_eq!(
+            cfg.upload_timeout(512 * 1024 * 1024),
+            Duration::from_secs(542)
+        );
+        cfg.upload_min_speed = ReadableSize(0);
+        assert_eq!(
+            cfg.upload_timeout(512 * 1024 * 1024),
+            Duration::from_secs(30)
+        );
+
+        cfg.upload_timeout = ReadableDuration::secs(0);
+        assert!(cfg.validate().is_err());
+        cfg = GrpcConfig::default();
+        cfg.max_send_msg_len = ReadableSize::gb(4);
+        assert!(cfg.validate().is_err());
+
+        let res =
+            toml::from_str::<TiKvConfig>("[import.grpc]\ncompression-type = 'gzip'\n").unwrap();
+        assert_eq!(res.import.grpc.compression_type, GrpcCompressionType::Gzip);
+    }
 }
@@ -0,0 +1,390 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+use engine_rocks::raw::{CFHandle, DBIterator, ReadOptions, SeekKey, Writable, DB};
+use engine_rocksdb::WriteBatch as RawBatch;
+use serde::{Deserialize, Serialize};
+
+use collections::{HashMap, HashSet};
+use txn_types::{Key, TimeStamp};
+
+use super::engine::EngineValue;
+use super::manifest::{hex_bytes, opt_hex_bytes};
+use super::Result;
+
+/// The column family recording the sources of writes and the duplicate keys
+/// detected in an engine.
+pub const CF_DUPLICATE: &str = "duplicate";
+
+// Keys of the duplicate CF are prefixed to separate the records.
+const DUPLICATE_PREFIX: &[u8] = b"d";
+const DUPLICATE_PREFIX_END: &[u8] = b"e";
+const SOURCE_PREFIX: &[u8] = b"s";
+const SOURCE_PREFIX_END: &[u8] = b"t";
+
+/// DuplicateKey records a key which is written more than once with different
+/// values at the same commit ts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DuplicateKey {
+    #[serde(with = "hex_bytes")]
+    pub key: Vec<u8>,
+    pub commit_ts: u64,
+    /// The value written before, and the source which wrote it. The value is
+    /// None if the key was deleted.
+    #[serde(with = "opt_hex_bytes")]
+    pub old_value: Option<Vec<u8>>,
+    pub old_source: String,
+    /// The value which overwrites the old one, and the source which wrote it.
+    #[serde(with = "opt_hex_bytes")]
+    pub new_value: Option<Vec<u8>>,
+    pub new_source: String,
+}
+
+/// Sources maps the names of sources to the ids stored with values.
+#[derive(Default)]
+struct Sources {
+    ids: HashMap<String, u64>,
+    names: HashMap<u64, String>,
+    next_id: u64,
+}
+
+/// DuplicateDetector checks every key written to an engine, and records the
+/// key in the duplicate CF if it has been written with a different value.
+///
+/// Values carry the id of the source which wrote them, the name of each
+/// source is recorded once in the duplicate CF, so that both writers of a
+/// duplicate key can be reported.
+pub struct DuplicateDetector {
+    sources: Mutex<Sources>,
+    // Hashes of the keys being detected and written. Detection and the
+    // following write of a key must be atomic, otherwise concurrent writes
+    // of the same key may not be detected, so batches with common keys are
+    // detected one by one.
+    writing: Mutex<HashSet<u64>>,
+    written: Condvar,
+    next_id: AtomicU64,
+}
+
+/// DetectGuard keeps other batches from detecting the keys of a batch until
+/// the batch is written.
+pub struct DetectGuard<'a> {
+    detector: &'a DuplicateDetector,
+    hashes: HashSet<u64>,
+}
+
+impl Drop for DetectGuard<'_> {
+    fn drop(&mut self) {
+        let mut writing = self.detector.writing.lock().unwrap();
+        for hash in &self.hashes {
+            writing.remove(hash);
+        }
+        self.detector.written.notify_all();
+    }
+}
+
+impl DuplicateDetector {
+    pub fn new(db: &DB) -> Result<DuplicateDetector> {
+        let mut sources = Sources::default();
+        let mut iter = new_cf_iter(db, SOURCE_PREFIX_END);
+        let mut valid = iter.seek(SeekKey::Key(SOURCE_PREFIX))?;
+        while valid {
+            let id = iter.key()[SOURCE_PREFIX.len()..]
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid source record"))?;
+            let name = String::from_utf8_lossy(iter.value()).into_owned();
+            sources.ids.insert(name.clone(), id);
+            sources.names.insert(id, name);
+            sources.next_id = sources.next_id.max(id + 1);
+            valid = iter.next()?;
+        }
+        // Records are never removed, so the number of records is the next id.
+        let next_id = load_duplicates(db)?.len() as u64;
+        Ok(DuplicateDetector {
+            sources: Mutex::new(sources),
+            writing: Mutex::default(),
+            written: Condvar::new(),
+            next_id: AtomicU64::new(next_id),
+        })
+    }
+
+    /// Checks `mutations` of raw keys at `commit_ts`, and puts the records
+    /// of the source and duplicate keys to `wb`.
+    ///
+    /// Returns the id of `source` which must be stored with the values, and
+    /// the guard which must be held until `wb` is written.
+    pub fn detect(
+        &self,
+        db: &DB,
+        wb: &RawBatch,
+        commit_ts: u64,
+        mutations: &[(&[u8], EngineValue<'_>)],
+        source: &str,
+    ) -> Result<(DetectGuard<'_>, u64)> {
+        let guard = self.lock_keys(commit_ts, mutations);
+        let handle = db.cf_handle(CF_DUPLICATE).unwrap();
+        let source_id = self.source_id(source);
+        // The record is put with every batch, in case the batch which first
+        // put it failed to be written.
+        let source_key = [SOURCE_PREFIX, &source_id.to_be_bytes()].concat();
+        wb.put_cf(handle, &source_key, source.as_bytes())?;
+
+        // Keys written by the same batch are not in the engine yet.
+        let mut batch_values = HashMap::default();
+        for (key, value) in mutations {
+            if let Some(old_value) = batch_values.insert(*key, *value) {
+                if old_value != *value {
+                    self.record(
+                        wb,
+                        handle,
+                        key,
+                        commit_ts,
+                        (old_value, Some(source_id)),
+                        (*value, source),
+                    )?;
+                }
+                continue;
+            }
+            let data_key = Key::from_raw(key)
+                .append_ts(TimeStamp::new(commit_ts))
+                .into_encoded();
+            if let Some(v) = db.get(&data_key)? {
+                let (old_value, old_source_id) = EngineValue::decode_with_source(&v)?;
+                if old_value != *value {
+                    self.record(
+                        wb,
+                        handle,
+                        key,
+                        commit_ts,
+                        (old_value, old_source_id),
+                        (*value, source),
+                    )?;
+                }
+            }
+        }
+        Ok((guard, source_id))
+    }
+
+    /// Waits until no other batch is writing the keys of `mutations`, and
+    /// marks them as being written.
+    fn lock_keys(&self, commit_ts: u64, mutations: &[(&[u8], EngineValue<'_>)]) -> DetectGuard<'_> {
+        let hashes: HashSet<_> = mutations
+            .iter()
+            .map(|(key, _)| {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                commit_ts.hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect();
+        let mut writing = self.writing.lock().unwrap();
+        while hashes.iter().any(|hash| writing.contains(hash)) {
+            writing = self.written.wait(writing).unwrap();
+        }
+        writing.extend(&hashes);
+        DetectGuard {
+            detector: self,
+            hashes,
+        }
+    }
+
+    fn source_id(&self, source: &str) -> u64 {
+        let mut sources = self.sources.lock().unwrap();
+        if let Some(id) = sources.ids.get(source) {
+            return *id;
+        }
+        let id = sources.next_id;
+        sources.next_id += 1;
+        sources.ids.insert(source.to_owned(), id);
+        sources.names.insert(id, source.to_owned());
+        id
+    }
+
+    /// Puts the record of a duplicate key to `wb`.
+    fn record(
+        &self,
+        wb: &RawBatch,
+        handle: &CFHandle,
+        key: &[u8],
+        commit_ts: u64,
+        (old_value, old_source_id): (EngineValue<'_>, Option<u64>),
+        (new_value, new_source): (EngineValue<'_>, &str),
+    ) -> Result<()> {
+        let old_source = old_source_id
+            .and_then(|id| self.sources.lock().unwrap().names.get(&id).cloned())
+            .unwrap_or_default();
+        let dup = DuplicateKey {
+            key: key.to_vec(),
+            commit_ts,
+            old_value: to_option(old_value),
+            old_source,
+            new_value: to_option(new_value),
+            new_source: new_source.to_owned(),
+        };
+        warn!("duplicate key detected"; "key" => log_wrappers::Value::key(key), "commit_ts" => commit_ts, "old_source" => %dup.old_source, "new_source" => %new_source);
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let dup_key = [DUPLICATE_PREFIX, &id.to_be_bytes()].concat();
+        let data = serde_json::to_vec(&dup).map_err(io::Error::from)?;
+        wb.put_cf(handle, &dup_key, &data)?;
+        Ok(())
+    }
+}
+
+fn to_option(value: EngineValue<'_>) -> Option<Vec<u8>> {
+    match value {
+        EngineValue::Put(v) => Some(v.to_vec()),
+        EngineValue::Delete => None,
+    }
+}
+
+/// Returns the duplicate keys recorded in `db` in the order of detection.
+pub fn load_duplicates(db: &DB) -> Result<Vec<DuplicateKey>> {
+    let mut iter = new_cf_iter(db, DUPLICATE_PREFIX_END);
+    let mut dups = Vec::new();
+    let mut valid = iter.seek(SeekKey::Key(DUPLICATE_PREFIX))?;
+    while valid {
+        let dup = serde_json::from_slice(iter.value()).map_err(io::Error::from)?;
+        dups.push(dup);
+        valid = iter.next()?;
+    }
+    Ok(dups)
+}
+
+fn new_cf_iter<'a>(db: &'a DB, upper_bound: &[u8]) -> DBIterator<&'a DB> {
+    let handle = db.cf_handle(CF_DUPLICATE).unwrap();
+    let mut ropts = ReadOptions::new();
+    ropts.fill_cache(false);
+    ropts.set_iterate_upper_bound(upper_bound.to_vec());
+    DBIterator::new_cf(db, handle, ropts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    use engine_rocks::raw::{ColumnFamilyOptions, DBOptions};
+    use engine_rocks::raw_util::{new_engine_opt, CFOptions};
+    use engine_traits::CF_DEFAULT;
+    use tempdir::TempDir;
+
+    fn new_db(path: &std::path::Path) -> DB {
+        let mut db_opts = DBOptions::new();
+        db_opts.create_if_missing(true);
+        let cfs_opts = vec![
+            CFOptions::new(CF_DEFAULT, ColumnFamilyOptions::new()),
+            CFOptions::new(CF_DUPLICATE, ColumnFamilyOptions::new()),
+        ];
+        new_engine_opt(path.to_str().unwrap(), db_opts, cfs_opts).unwrap()
+    }
+
+    // Writes the mutations like `Engine::write_mutations`.
+    fn write(
+        db: &DB,
+        detector: &DuplicateDetector,
+        mutations: &[(&[u8], EngineValue<'_>)],
+        source: &str,
+    ) {
+        let wb = RawBatch::new();
+        let (_guard, source_id) = detector.detect(db, &wb, 10, mutations, source).unwrap();
+        for (key, value) in mutations {
+            let key = Key::from_raw(key).append_ts(TimeStamp::new(10));
+            wb.put(key.as_encoded(), &value.encode_with_source(Some(source_id)))
+                .unwrap();
+        }
+        db.write(&wb).unwrap();
+    }
+
+    #[test]
+    fn test_detect() {
+        let temp_dir = TempDir::new("test_detect").unwrap();
+        let db = new_db(temp_dir.path());
+        let detector = DuplicateDetector::new(&db).unwrap();
+
+        write(&db, &detector, &[(&b"a"[..], EngineValue::Put(b"1"))], "s1");
+        write(&db, &detector, &[(&b"a"[..], EngineValue::Put(b"1"))], "s2");
+        assert!(load_duplicates(&db).unwrap().is_empty());
+        let mutations = [
+            (&b"a"[..], EngineValue::Delete),
+            (&b"b"[..], EngineValue::Put(b"1")),
+            (&b"b"[..], EngineValue::Put(b"")),
+        ];
+        write(&db, &detector, &mutations, "s3");
+        let mut dups = vec![
+            DuplicateKey {
+                key: b"a".to_vec(),
+                commit_ts: 10,
+                old_value: Some(b"1".to_vec()),
+                old_source: "s2".to_owned(),
+                new_value: None,
+                new_source: "s3".to_owned(),
+            },
+            DuplicateKey {
+                key: b"b".to_vec(),
+                commit_ts: 10,
+                old_value: Some(b"1".to_vec()),
+                old_source: "s3".to_owned(),
+                new_value: Some(vec![]),
+                new_source: "s3".to_owned(),
+            },
+        ];
+        assert_eq!(load_duplicates(&db).unwrap(), dups);
+
+        // Sources and duplicate records are recovered from the engine.
+        drop(detector);
+        let detector = DuplicateDetector::new(&db).unwrap();
+        write(&db, &detector, &[(&b"b"[..], EngineValue::Put(b"2"))], "s4");
+        dups.push(DuplicateKey {
+            key: b"b".to_vec(),
+            commit_ts: 10,
+            old_value: Some(vec![]),
+            old_source: "s3".to_owned(),
+            new_value: Some(b"2".to_vec()),
+            new_source: "s4".to_owned(),
+        });
+        assert_eq!(load_duplicates(&db).unwrap(), dups);
+    }
+
+    #[test]
+    fn test_detect_concurrently() {
+        let temp_dir = TempDir::new("test_detect_concurrently").unwrap();
+        let db = Arc::new(new_db(temp_dir.path()));
+        let detector = Arc::new(DuplicateDetector::new(&db).unwrap());
+
+        // Every key is written by both sources with different values, so
+        // every key must be detected exactly once.
+        let handles: Vec<_> = ["s1", "s2"]
+            .iter()
+            .map(|&source| {
+                let db = Arc::clone(&db);
+                let detector = Arc::clone(&detector);
+                thread::spawn(move || {
+                    for i in 0..100u8 {
+                        let key = [i];
+                        let mutations = [(&key[..], EngineValue::Put(source.as_bytes()))];
+                        write(&db, &detector, &mutations, source);
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let mut keys: Vec<_> = load_duplicates(&db)
+            .unwrap()
+            .into_iter()
+            .map(|dup| dup.key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, (0..100u8).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+}
@@ -1,13 +1,17 @@
 // Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::cmp;
+use std::collections::BTreeSet;
+use std::convert::TryInto;
 use std::fmt;
 use std::i32;
 use std::io;
 use std::ops::Deref;
 use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
+use serde::Serialize;
 use uuid::Uuid;
 
 use kvproto::import_kvpb::mutation::Op as MutationOp;
@@ -16,11 +20,11 @@ use kvproto::import_sstpb::*;
 
 use engine_rocks::raw::{
     BlockBasedOptions, Cache, ColumnFamilyOptions, DBIterator, DBOptions, Env, LRUCacheOptions,
-    ReadOptions, Writable, DB,
+    ReadOptions, SeekKey, Writable, DB,
 };
 use engine_rocks::raw_util::{new_engine_opt, CFOptions};
 use engine_rocks::{
-    RangeProperties, RangePropertiesCollectorFactory, SizeProperties,
+    get_env, RangeProperties, RangePropertiesCollectorFactory, SizeProperties,
     UserCollectedPropertiesDecoder,
 };
 use engine_rocksdb::{
@@ -32,10 +36,106 @@ use tikv::storage::mvcc::{Write, WriteType};
 use tikv_util::config::MB;
 use txn_types::{is_short_value, Key, TimeStamp};
 
+use super::checksum::EngineChecksum;
 use super::common::*;
-use super::Result;
+use super::duplicate::*;
+use super::manifest::hex_bytes;
+use super::{Error, Result};
 use crate::import::stream::SSTFile;
-use security::SecurityManager;
+use encryption_export::DataKeyManager;
+
+// Values stored in the engine are prefixed by the type of the mutation.
+const VALUE_PREFIX_PUT: u8 = b'P';
+const VALUE_PREFIX_DELETE: u8 = b'D';
+// Values written with duplicate detection carry the id of their source after
+// the prefix.
+const VALUE_PREFIX_PUT_WITH_SOURCE: u8 = b'p';
+const VALUE_PREFIX_DELETE_WITH_SOURCE: u8 = b'd';
+const SOURCE_ID_LEN: usize = 8;
+
+/// EngineValue is a mutation of a key stored in the engine.
+///
+/// A deletion is marked explicitly, since an empty value is a legitimate
+/// value to put.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EngineValue<'a> {
+    Put(&'a [u8]),
+    Delete,
+}
+
+impl<'a> EngineValue<'a> {
+    pub fn decode(value: &'a [u8]) -> Result<EngineValue<'a>> {
+        Self::decode_with_source(value).map(|(v, _)| v)
+    }
+
+    /// Decodes the value and the id of its source, the id is None if the
+    /// value is written without duplicate detection.
+    pub fn decode_with_source(value: &'a [u8]) -> Result<(EngineValue<'a>, Option<u64>)> {
+        let (prefix, rest) = value.split_first().ok_or(Error::InvalidEngineValue)?;
+        let (source_id, rest) = match *prefix {
+            VALUE_PREFIX_PUT | VALUE_PREFIX_DELETE => (None, rest),
+            VALUE_PREFIX_PUT_WITH_SOURCE | VALUE_PREFIX_DELETE_WITH_SOURCE
+                if rest.len() >= SOURCE_ID_LEN =>
+            {
+                let (id, rest) = rest.split_at(SOURCE_ID_LEN);
+                (Some(u64::from_be_bytes(id.try_into().unwrap())), rest)
+            }
+            _ => return Err(Error::InvalidEngineValue),
+        };
+        match (*prefix, rest) {
+            (VALUE_PREFIX_PUT, v) | (VALUE_PREFIX_PUT_WITH_SOURCE, v) => {
+                Ok((EngineValue::Put(v), source_id))
+            }
+            (VALUE_PREFIX_DELETE, []) | (VALUE_PREFIX_DELETE_WITH_SOURCE, []) => {
+                Ok((EngineValue::Delete, source_id))
+            }
+            _ => Err(Error::InvalidEngineValue),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with_source(None)
+    }
+
+    /// Encodes the value with the id of its source if it is given.
+    pub fn encode_with_source(&self, source_id: Option<u64>) -> Vec<u8> {
+        let (prefix, v) = match (self, source_id) {
+            (EngineValue::Put(v), None) => (VALUE_PREFIX_PUT, *v),
+            (EngineValue::Put(v), Some(_)) => (VALUE_PREFIX_PUT_WITH_SOURCE, *v),
+            (EngineValue::Delete, None) => (VALUE_PREFIX_DELETE, &[][..]),
+            (EngineValue::Delete, Some(_)) => (VALUE_PREFIX_DELETE_WITH_SOURCE, &[][..]),
+        };
+        let mut value = Vec::with_capacity(1 + SOURCE_ID_LEN + v.len());
+        value.push(prefix);
+        if let Some(id) = source_id {
+            value.extend_from_slice(&id.to_be_bytes());
+        }
+        value.extend_from_slice(v);
+        value
+    }
+}
+
+/// EngineStats describes the data in an engine.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EngineStats {
+    /// Number of KV pairs estimated from the properties of SST files.
+    pub approximate_kvs: u64,
+    /// Number of KV pairs, including all versions and deletions.
+    pub kvs: u64,
+    /// Number of deletions.
+    pub deleted_kvs: u64,
+    /// Total size from `SizeProperties`.
+    pub total_size: u64,
+    /// The smallest and the largest raw keys.
+    #[serde(with = "hex_bytes")]
+    pub smallest_key: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    pub largest_key: Vec<u8>,
+    pub sst_files: u64,
+    /// Distinct commit ts of KV pairs in ascending order.
+    pub commit_ts: Vec<u64>,
+}
 
 /// Engine wraps rocksdb::DB with customized options to support efficient bulk
 /// write.
@@ -43,7 +143,11 @@ pub struct Engine {
     db: Arc<DB>,
     uuid: Uuid,
     db_cfg: DbConfig,
-    security_mgr: Arc<SecurityManager>,
+    key_manager: Option<Arc<DataKeyManager>>,
+    sst_memory_budget: Arc<SSTMemoryBudget>,
+    // The directory of SSTs written to disk.
+    sst_dir: PathBuf,
+    duplicate: Option<DuplicateDetector>,
 }
 
 impl Engine {
@@ -51,36 +155,97 @@ impl Engine {
         path: P,
         uuid: Uuid,
         db_cfg: DbConfig,
-        security_mgr: Arc<SecurityManager>,
+        key_manager: Option<Arc<DataKeyManager>>,
+        detect_duplicate: bool,
     ) -> Result<Engine> {
         let db = {
-            let (db_opts, cf_opts) = tune_dboptions_for_bulk_load(&db_cfg);
-            new_engine_opt(path.as_ref().to_str().unwrap(), db_opts, vec![cf_opts])?
+            let (db_opts, cf_opts) =
+                tune_dboptions_for_bulk_load(&db_cfg, key_manager.clone(), detect_duplicate)?;
+            // The duplicate CF is always created, so that duplicate keys can
+            // be read no matter how the engine is opened later.
+            let dup_opts = CFOptions::new(CF_DUPLICATE, ColumnFamilyOptions::new());
+            new_engine_opt(
+                path.as_ref().to_str().unwrap(),
+                db_opts,
+                vec![cf_opts, dup_opts],
+            )?
+        };
+        let duplicate = if detect_duplicate {
+            Some(DuplicateDetector::new(&db)?)
+        } else {
+            None
         };
         Ok(Engine {
             db: Arc::new(db),
             uuid,
             db_cfg,
-            security_mgr,
+            key_manager,
+            sst_memory_budget: Arc::default(),
+            sst_dir: path.as_ref().to_owned(),
+            duplicate,
         })
     }
 
+    /// Sets the memory budget of SSTs generated from the engine, SSTs are
+    /// all built in memory by default.
+    pub fn set_sst_memory_budget(&mut self, budget: Arc<SSTMemoryBudget>) {
+        self.sst_memory_budget = budget;
+    }
+
+    /// Sets the directory of SSTs written to disk once the memory budget is
+    /// used up, which is the engine directory by default.
+    pub fn set_sst_dir(&mut self, dir: PathBuf) {
+        self.sst_dir = dir;
+    }
+
     pub fn uuid(&self) -> Uuid {
         self.uuid
     }
 
-    pub fn write(&self, batch: WriteBatch) -> Result<usize> {
+    /// Writes the batch to the engine, `source` identifies the writer of
+    /// the batch if duplicate keys are detected.
+    pub fn write(&self, batch: WriteBatch, source: &str) -> Result<usize> {
+        let mutations: Vec<_> = batch
+            .get_mutations()
+            .iter()
+            .map(|m| match m.get_op() {
+                MutationOp::Put => (m.get_key(), EngineValue::Put(m.get_value())),
+            })
+            .collect();
+        self.write_mutations(batch.get_commit_ts(), &mutations, source)
+    }
+
+    pub fn write_v3(&self, commit_ts: u64, pairs: &[KvPair], source: &str) -> Result<usize> {
+        let mutations: Vec<_> = pairs
+            .iter()
+            .map(|p| (p.get_key(), EngineValue::Put(p.get_value())))
+            .collect();
+        self.write_mutations(commit_ts, &mutations, source)
+    }
+
+    /// Writes puts and deletions of raw keys at `commit_ts` to the engine.
+    pub fn write_mutations(
+        &self,
+        commit_ts: u64,
+        mutations: &[(&[u8], EngineValue<'_>)],
+        source: &str,
+    ) -> Result<usize> {
         // Just a guess.
-        let wb_cap = cmp::min(batch.get_mutations().len() * 128, MB as usize);
+        let wb_cap = cmp::min(mutations.len() * 128, MB as usize);
         let wb = RawBatch::with_capacity(wb_cap);
-        let commit_ts = TimeStamp::new(batch.get_commit_ts());
-        for m in batch.get_mutations().iter() {
-            match m.get_op() {
-                MutationOp::Put => {
-                    let k = Key::from_raw(m.get_key()).append_ts(commit_ts);
-                    wb.put(k.as_encoded(), m.get_value()).unwrap();
-                }
+        // The guard is held until the batch is written.
+        let (_guard, source_id) = match &self.duplicate {
+            Some(d) => {
+                let (guard, id) = d.detect(&self.db, &wb, commit_ts, mutations, source)?;
+                (Some(guard), Some(id))
             }
+            None => (None, None),
+        };
+        let ts = TimeStamp::new(commit_ts);
+        for (key, value) in mutations {
+            let k = Key::from_raw(key).append_ts(ts);
+            wb.put(k.as_encoded(), &value.encode_with_source(source_id))
+                .unwrap();
         }
 
         let size = wb.data_size();
@@ -89,20 +254,81 @@ impl Engine {
         Ok(size)
     }
 
-    pub fn write_v3(&self, commit_ts: u64, pairs: &[KvPair]) -> Result<usize> {
-        // Just a guess.
-        let wb_cap = cmp::min(pairs.len() * 128, MB as usize);
-        let wb = RawBatch::with_capacity(wb_cap);
-        let commit_ts = TimeStamp::new(commit_ts);
-        for p in pairs {
-            let k = Key::from_raw(p.get_key()).append_ts(commit_ts);
-            wb.put(k.as_encoded(), p.get_value()).unwrap();
+    /// Flushes all column families of the engine.
+    pub fn flush(&self, sync: bool) -> Result<()> {
+        self.db.flush(sync)?;
+        let handle = self.db.cf_handle(CF_DUPLICATE).unwrap();
+        self.db.flush_cf(handle, sync)?;
+        Ok(())
+    }
+
+    /// Computes the checksum of the engine, only the latest version of each
+    /// key is counted, and deleted keys are skipped, which is the same as
+    /// what TiKV reads after the engine is imported.
+    pub fn checksum(&self) -> Result<EngineChecksum> {
+        let mut checksum = EngineChecksum::default();
+        let mut iter = self.new_iter(true);
+        let mut last_key = Vec::new();
+        let mut valid = iter.seek(SeekKey::Start)?;
+        while valid {
+            let (key, _) = Key::split_on_ts_for(iter.key())?;
+            // Versions of a key are sorted by commit ts in descending order.
+            if key != last_key.as_slice() {
+                last_key = key.to_vec();
+                if let EngineValue::Put(value) = EngineValue::decode(iter.value())? {
+                    let raw_key = Key::from_encoded_slice(key).into_raw()?;
+                    checksum.update(&raw_key, value);
+                }
+            }
+            valid = iter.next()?;
         }
+        Ok(checksum)
+    }
 
-        let size = wb.data_size();
-        self.write_without_wal(&wb)?;
+    /// Collects statistics of the engine. Properties of SST files only cover
+    /// flushed data, the other statistics are collected by a full scan.
+    pub fn stats(&self) -> Result<EngineStats> {
+        let mut stats = EngineStats {
+            total_size: self.get_size_properties()?.total_size,
+            ..Default::default()
+        };
+        let collection = self.get_properties_of_all_tables()?;
+        for (_, v) in &*collection {
+            let props = RangeProperties::decode(&UserCollectedPropertiesDecoder(
+                v.user_collected_properties(),
+            ))?;
+            if let Some((_, offsets)) = props.offsets.last() {
+                stats.approximate_kvs += offsets.keys;
+            }
+            stats.sst_files += 1;
+        }
 
-        Ok(size)
+        let mut commit_ts = BTreeSet::new();
+        let mut iter = self.new_iter(true);
+        let mut valid = iter.seek(SeekKey::Start)?;
+        while valid {
+            let (key, ts) = Key::split_on_ts_for(iter.key())?;
+            if stats.kvs == 0 {
+                stats.smallest_key = Key::from_encoded_slice(key).into_raw()?;
+            }
+            stats.kvs += 1;
+            if EngineValue::decode(iter.value())? == EngineValue::Delete {
+                stats.deleted_kvs += 1;
+            }
+            commit_ts.insert(ts.into_inner());
+            valid = iter.next()?;
+        }
+        if iter.seek(SeekKey::End)? {
+            let (key, _) = Key::split_on_ts_for(iter.key())?;
+            stats.largest_key = Key::from_encoded_slice(key).into_raw()?;
+        }
+        stats.commit_ts = commit_ts.into_iter().collect();
+        Ok(stats)
+    }
+
+    /// Returns the duplicate keys detected while writing the engine.
+    pub fn duplicate_keys(&self) -> Result<Vec<DuplicateKey>> {
+        load_duplicates(&self.db)
     }
 
     pub fn new_iter(&self, verify_checksum: bool) -> DBIterator<Arc<DB>> {
@@ -113,7 +339,12 @@ impl Engine {
     }
 
     pub fn new_sst_writer(&self) -> Result<SSTWriter> {
-        SSTWriter::new(&self.db_cfg, &self.security_mgr, self.db.path())
+        SSTWriter::new(
+            &self.db_cfg,
+            self.key_manager.clone(),
+            &self.sst_memory_budget,
+            self.sst_dir.to_str().unwrap(),
+        )
     }
 
     pub fn get_size_properties(&self) -> Result<SizeProperties> {
@@ -157,8 +388,61 @@ impl fmt::Debug for Engine {
     }
 }
 
+/// SSTMemoryBudget limits the total size of SSTs built in memory.
+///
+/// The size of an SST is unknown until it is finished, so a writer reserves
+/// `sst_size` of the budget when it is created, and builds its SSTs in memory
+/// only if the reservation succeeds, otherwise they are written to disk. The
+/// reservation is settled to the real size of the SSTs once they are finished.
+pub struct SSTMemoryBudget {
+    capacity: u64,
+    sst_size: u64,
+    used: AtomicU64,
+}
+
+impl SSTMemoryBudget {
+    pub fn new(capacity: u64, sst_size: u64) -> SSTMemoryBudget {
+        SSTMemoryBudget {
+            capacity,
+            sst_size,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserves `sst_size` of the budget, returns false if the budget is not
+    /// enough.
+    fn reserve(&self) -> bool {
+        let capacity = self.capacity;
+        let size = self.sst_size;
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                match used.checked_add(size) {
+                    Some(new_used) if used < capacity && new_used <= capacity => Some(new_used),
+                    _ => None,
+                }
+            })
+            .is_ok()
+    }
+
+    fn acquire(&self, size: u64) {
+        self.used.fetch_add(size, Ordering::SeqCst);
+    }
+
+    fn release(&self, size: u64) {
+        self.used.fetch_sub(size, Ordering::SeqCst);
+    }
+}
+
+impl Default for SSTMemoryBudget {
+    fn default() -> SSTMemoryBudget {
+        SSTMemoryBudget::new(u64::MAX, 0)
+    }
+}
+
 pub struct LazySSTInfo {
     env: Arc<Env>,
+    // The budget charged by the SST, if it is kept in memory.
+    memory_budget: Option<Arc<SSTMemoryBudget>>,
     file_path: PathBuf,
     pub(crate) file_size: u64,
     pub(crate) range: Range,
@@ -177,14 +461,23 @@ impl fmt::Debug for LazySSTInfo {
 }
 
 impl LazySSTInfo {
-    fn new(env: Arc<Env>, info: ExternalSstFileInfo, cf_name: &'static str) -> Self {
+    fn new(
+        env: Arc<Env>,
+        memory_budget: Option<Arc<SSTMemoryBudget>>,
+        info: ExternalSstFileInfo,
+        cf_name: &'static str,
+    ) -> Self {
         // This range doesn't contain the data prefix, like the region range.
         let mut range = Range::default();
         range.set_start(keys::origin_key(info.smallest_key()).to_owned());
         range.set_end(keys::origin_key(info.largest_key()).to_owned());
 
+        if let Some(budget) = &memory_budget {
+            budget.acquire(info.file_size());
+        }
         Self {
             env,
+            memory_budget,
             file_path: info.file_path(),
             file_size: info.file_size(),
             range,
@@ -198,22 +491,31 @@ impl LazySSTInfo {
             .new_sequential_file(self.file_path.to_str().unwrap(), EnvOptions::new())?)
     }
 
-    pub(crate) fn into_sst_file(self) -> Result<SSTFile> {
+    fn compute_crc32(&self) -> Result<u32> {
         let mut seq_file = self.open()?;
-
-        // TODO: If we can compute the CRC simultaneously with upload, we don't
-        // need to open() and read() the file twice.
         let mut writer = Crc32Writer {
             digest: crc32fast::Hasher::new(),
             length: 0,
         };
         io::copy(&mut seq_file, &mut writer)?;
+        if writer.length != self.file_size {
+            return Err(Error::FileCorrupted {
+                path: self.file_path.clone(),
+                reason: format!("length {}, expect {}", writer.length, self.file_size),
+            });
+        }
+        Ok(writer.digest.finalize())
+    }
 
+    /// Computes the CRC of the file for uploading. The CRC is sent ahead of
+    /// the data, so it is computed only for SSTs to upload, rather than for
+    /// every SST written, e.g. SSTs of other CFs generated by a resplit.
+    pub(crate) fn into_sst_file(self) -> Result<SSTFile> {
         let mut meta = SstMeta::default();
         meta.set_uuid(Uuid::new_v4().as_bytes().to_vec());
         meta.set_range(self.range.clone());
-        meta.set_crc32(writer.digest.finalize());
-        meta.set_length(writer.length);
+        meta.set_crc32(self.compute_crc32()?);
+        meta.set_length(self.file_size);
         meta.set_cf_name(self.cf_name.to_owned());
 
         Ok(SSTFile { meta, info: self })
@@ -230,6 +532,9 @@ impl Drop for LazySSTInfo {
                 warn!("cleanup SST failed"; "file_path" => ?self.file_path, "err" => %err);
             }
         }
+        if let Some(budget) = &self.memory_budget {
+            budget.release(self.file_size);
+        }
     }
 }
 
@@ -251,23 +556,45 @@ impl io::Write for Crc32Writer {
 }
 
 pub struct SSTWriter {
+    // SST files are kept in memory, or on disk and encrypted if encryption is
+    // enabled. Files are read back through this env, so the uploaded data is
+    // always decrypted, since TiKV encrypts ingested files with its own data
+    // keys.
     env: Arc<Env>,
-    // we need to preserve base env for reading raw file while env is an encrypted env
-    base_env: Option<Arc<Env>>,
+    // The budget to charge if SST files are kept in memory.
+    memory_budget: Option<Arc<SSTMemoryBudget>>,
+    // The budget reserved until the SST files are finished.
+    reserved: u64,
+    finished: bool,
     default: SstFileWriter,
+    default_path: String,
     default_entries: u64,
     write: SstFileWriter,
+    write_path: String,
     write_entries: u64,
 }
 
 impl SSTWriter {
     pub fn new(
         db_cfg: &DbConfig,
-        _security_mgr: &SecurityManager,
+        key_manager: Option<Arc<DataKeyManager>>,
+        memory_budget: &Arc<SSTMemoryBudget>,
         path: &str,
     ) -> Result<SSTWriter> {
-        let env = Arc::new(Env::new_mem());
-        let base_env = None;
+        // SST files are written to `path` if the memory budget is used up.
+        // Files in memory are never persisted, so they are not encrypted,
+        // which saves registering them in the key dictionary.
+        let (env, memory_budget, reserved) = if memory_budget.reserve() {
+            let reserved = memory_budget.sst_size;
+            (
+                Arc::new(Env::new_mem()),
+                Some(Arc::clone(memory_budget)),
+                reserved,
+            )
+        } else {
+            let env = get_env(None, key_manager).map_err(Error::Security)?;
+            (env, None, 0)
+        };
         let uuid = Uuid::new_v4().to_string();
         // Placeholder. SstFileWriter don't actually use block cache.
         let cache = None;
@@ -279,21 +606,27 @@ impl SSTWriter {
         default_opts.set_env(Arc::clone(&env));
         default_opts.compression_per_level(&db_cfg.defaultcf.compression_per_level);
         let mut default = SstFileWriter::new(EnvOptions::new(), default_opts);
-        default.open(&format!("{}{}.{}:default", path, MAIN_SEPARATOR, uuid))?;
+        let default_path = format!("{}{}.{}:default", path, MAIN_SEPARATOR, uuid);
+        default.open(&default_path)?;
 
         // Creates a writer for write CF
         let mut write_opts = db_cfg.writecf.build_opt(&cache, None);
         write_opts.set_env(Arc::clone(&env));
         write_opts.compression_per_level(&db_cfg.writecf.compression_per_level);
         let mut write = SstFileWriter::new(EnvOptions::new(), write_opts);
-        write.open(&format!("{}{}.{}:write", path, MAIN_SEPARATOR, uuid))?;
+        let write_path = format!("{}{}.{}:write", path, MAIN_SEPARATOR, uuid);
+        write.open(&write_path)?;
 
         Ok(SSTWriter {
             env,
-            base_env,
+            memory_budget,
+            reserved,
+            finished: false,
             default,
+            default_path,
             default_entries: 0,
             write,
+            write_path,
             write_entries: 0,
         })
     }
@@ -315,28 +648,72 @@ impl SSTWriter {
         Ok(())
     }
 
+    /// Writes a tombstone for `key`, which will delete the user key in TiKV
+    /// at the commit ts encoded in `key`.
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let k = keys::data_key(key);
+        let (_, commit_ts) = Key::split_on_ts_for(key)?;
+        let w = Write::new(WriteType::Delete, commit_ts, None);
+        self.write.put(&k, &w.as_ref().to_bytes())?;
+        self.write_entries += 1;
+        Ok(())
+    }
+
     pub fn finish(&mut self) -> Result<Vec<LazySSTInfo>> {
         let mut infos = Vec::with_capacity(2);
         if self.default_entries > 0 {
             let info = self.default.finish()?;
             infos.push(LazySSTInfo::new(
-                Arc::clone(self.base_env.as_ref().unwrap_or_else(|| &self.env)),
+                Arc::clone(&self.env),
+                self.memory_budget.clone(),
                 info,
                 CF_DEFAULT,
             ));
+        } else {
+            // Files of CFs without entries can not be finished, delete them
+            // so that no file is left behind.
+            self.env.delete_file(&self.default_path)?;
         }
         if self.write_entries > 0 {
             let info = self.write.finish()?;
             infos.push(LazySSTInfo::new(
-                Arc::clone(self.base_env.as_ref().unwrap_or_else(|| &self.env)),
+                Arc::clone(&self.env),
+                self.memory_budget.clone(),
                 info,
                 CF_WRITE,
             ));
+        } else {
+            // Files of CFs without entries can not be finished, delete them
+            // so that no file is left behind.
+            self.env.delete_file(&self.write_path)?;
+        }
+        // The budget has been charged by the real size of the SSTs.
+        if let Some(budget) = &self.memory_budget {
+            budget.release(self.reserved);
+            self.reserved = 0;
         }
+        self.finished = true;
         Ok(infos)
     }
 }
 
+impl Drop for SSTWriter {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.memory_budget {
+            budget.release(self.reserved);
+        }
+        if self.finished {
+            return;
+        }
+        // The files of an unfinished writer are never used.
+        for path in &[&self.default_path, &self.write_path] {
+            if let Err(e) = self.env.delete_file(path) {
+                warn!("cleanup unfinished SST failed"; "file_path" => %path, "err" => %e);
+            }
+        }
+    }
+}
+
 /// Gets a set of approximately equal size ranges from `props`.
 /// The maximum number of ranges cannot exceed `max_ranges`,
 /// and the minimum number of ranges cannot be smaller than `min_range_size`
@@ -371,10 +748,19 @@ pub fn get_approximate_ranges(
     ranges
 }
 
-fn tune_dboptions_for_bulk_load(opts: &DbConfig) -> (DBOptions, CFOptions<'_>) {
+fn tune_dboptions_for_bulk_load(
+    opts: &DbConfig,
+    key_manager: Option<Arc<DataKeyManager>>,
+    detect_duplicate: bool,
+) -> Result<(DBOptions, CFOptions<'_>)> {
     const DISABLED: i32 = i32::MAX;
 
     let mut db_opts = DBOptions::new();
+    if key_manager.is_some() {
+        // Encrypts all files of the engine with data keys of the key manager.
+        let env = get_env(None, key_manager).map_err(Error::Security)?;
+        db_opts.set_env(env);
+    }
     db_opts.create_if_missing(true);
     db_opts.enable_statistics(false);
     // Vector memtable doesn't support concurrent write.
@@ -394,7 +780,11 @@ fn tune_dboptions_for_bulk_load(opts: &DbConfig) -> (DBOptions, CFOptions<'_>) {
     // Consider using a large write buffer but be careful about OOM.
     cf_opts.set_write_buffer_size(opts.defaultcf.write_buffer_size.0);
     cf_opts.set_target_file_size_base(opts.defaultcf.write_buffer_size.0);
-    cf_opts.set_vector_memtable_factory(opts.defaultcf.write_buffer_size.0);
+    // Point lookups of duplicate detection on a vector memtable need to sort
+    // the whole memtable, so the default skiplist memtable is used then.
+    if !detect_duplicate {
+        cf_opts.set_vector_memtable_factory(opts.defaultcf.write_buffer_size.0);
+    }
     cf_opts.set_max_write_buffer_number(opts.defaultcf.max_write_buffer_number);
     // Disable compaction and rate limit.
     cf_opts.set_disable_auto_compactions(true);
@@ -405,19 +795,20 @@ fn tune_dboptions_for_bulk_load(opts: &DbConfig) -> (DBOptions, CFOptions<'_>) {
     // Add size properties to get approximate ranges wihout scan.
     let f = Box::new(RangePropertiesCollectorFactory::default());
     cf_opts.add_table_properties_collector_factory("tikv.size-properties-collector", f);
-    (db_opts, CFOptions::new(CF_DEFAULT, cf_opts))
+    Ok((db_opts, CFOptions::new(CF_DEFAULT, cf_opts)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::import::test_helpers::new_key_manager;
 
     use engine_rocks::raw::IngestExternalFileOptions;
     use engine_rocks::raw_util::new_engine_opt;
     use engine_traits::MiscExt;
     use kvproto::kvrpcpb::IsolationLevel;
     use kvproto::metapb::{Peer, Region};
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io;
     use tempdir::TempDir;
 
@@ -427,7 +818,6 @@ mod tests {
         rngs::{OsRng, StdRng},
         RngCore, SeedableRng,
     };
-    use security::SecurityManager;
     use tikv::storage::config::BlockCacheConfig;
     use tikv::storage::mvcc::MvccReader;
 
@@ -435,8 +825,7 @@ mod tests {
         let dir = TempDir::new("test_import_engine").unwrap();
         let uuid = Uuid::new_v4();
         let db_cfg = DbConfig::default();
-        let security_mgr = Arc::default();
-        let engine = Engine::new(dir.path(), uuid, db_cfg, security_mgr).unwrap();
+        let engine = Engine::new(dir.path(), uuid, db_cfg, None, false).unwrap();
         (dir, engine)
     }
 
@@ -476,11 +865,60 @@ mod tests {
         let n = 10;
         let commit_ts = 10;
         let wb = new_write_batch(n, commit_ts);
-        engine.write(wb).unwrap();
+        engine.write(wb, "test").unwrap();
 
         for i in 0..n {
-            let key = new_encoded_key(i, commit_ts);
-            assert_eq!(engine.get(&key).unwrap().unwrap(), &[i]);
+            assert_eq!(get_engine_value(&engine, i, commit_ts), &[i]);
+        }
+    }
+
+    fn get_engine_value(engine: &Engine, i: u8, ts: u64) -> Vec<u8> {
+        let v = engine.get(&new_encoded_key(i, ts)).unwrap().unwrap();
+        match EngineValue::decode(&v).unwrap() {
+            EngineValue::Put(v) => v.to_vec(),
+            EngineValue::Delete => panic!("key {} is deleted", i),
+        }
+    }
+
+    #[test]
+    fn test_write_delete() {
+        let (_dir, engine) = new_engine();
+
+        // An empty value is a put rather than a deletion.
+        let commit_ts = 10;
+        let mut wb = new_write_batch(2, commit_ts);
+        wb.mut_mutations()[1].set_value(vec![]);
+        engine.write(wb, "test").unwrap();
+        assert_eq!(get_engine_value(&engine, 0, commit_ts), vec![0]);
+        assert!(get_engine_value(&engine, 1, commit_ts).is_empty());
+
+        let commit_ts = 20;
+        let mutations = vec![
+            (&[0u8][..], EngineValue::Delete),
+            (&[1u8][..], EngineValue::Put(b"1")),
+        ];
+        engine
+            .write_mutations(commit_ts, &mutations, "test")
+            .unwrap();
+        let v = engine.get(&new_encoded_key(0, commit_ts)).unwrap().unwrap();
+        assert_eq!(EngineValue::decode(&v).unwrap(), EngineValue::Delete);
+        assert_eq!(get_engine_value(&engine, 1, commit_ts), b"1");
+
+        for v in &[&b""[..], b"X1", b"D1", b"p1234567", b"d12345678x"] {
+            assert!(EngineValue::decode(v).is_err());
+        }
+
+        // The id of the source is kept with the value.
+        for value in &[
+            EngineValue::Put(b"1"),
+            EngineValue::Put(b""),
+            EngineValue::Delete,
+        ] {
+            for source_id in &[None, Some(0), Some(u64::MAX)] {
+                let v = value.encode_with_source(*source_id);
+                let decoded = EngineValue::decode_with_source(&v).unwrap();
+                assert_eq!(decoded, (*value, *source_id));
+            }
         }
     }
 
@@ -491,33 +929,308 @@ mod tests {
         let n = 10;
         let commit_ts = 10;
         let pairs = new_kv_pairs(n);
-        engine.write_v3(commit_ts, &pairs).unwrap();
+        engine.write_v3(commit_ts, &pairs, "test").unwrap();
 
         for i in 0..n {
-            let key = new_encoded_key(i, commit_ts);
-            assert_eq!(engine.get(&key).unwrap().unwrap(), &[i]);
+            assert_eq!(get_engine_value(&engine, i, commit_ts), &[i]);
+        }
+    }
+
+    #[test]
+    fn test_checksum() {
+        let (_dir, engine) = new_engine();
+
+        let pairs = new_kv_pairs(5);
+        engine.write_v3(10, &pairs, "test").unwrap();
+        // Key 1 is updated, key 2 is deleted and key 3 is emptied later.
+        let mutations = vec![
+            (&[1u8][..], EngineValue::Put(&[100])),
+            (&[2u8][..], EngineValue::Delete),
+            (&[3u8][..], EngineValue::Put(&[])),
+        ];
+        engine.write_mutations(20, &mutations, "test").unwrap();
+
+        let mut expected = EngineChecksum::default();
+        for (k, v) in &[(0u8, &[0u8][..]), (1, &[100]), (3, &[]), (4, &[4])] {
+            expected.update(&[*k], v);
         }
+        let checksum = engine.checksum().unwrap();
+        assert_eq!(checksum, expected);
+        assert_eq!(checksum.total.total_kvs, 4);
+        assert_eq!(checksum.total.total_bytes, 7);
+    }
+
+    #[test]
+    fn test_stats() {
+        let (_dir, engine) = new_engine();
+        assert_eq!(engine.stats().unwrap(), EngineStats::default());
+
+        let pairs = new_kv_pairs(4);
+        engine.write_v3(10, &pairs, "test").unwrap();
+        let mutations = vec![
+            (&[1u8][..], EngineValue::Put(&[])),
+            (&[2u8][..], EngineValue::Delete),
+        ];
+        engine.write_mutations(20, &mutations, "test").unwrap();
+
+        // Data in memtables is not counted by properties.
+        let stats = engine.stats().unwrap();
+        assert_eq!(stats.approximate_kvs, 0);
+        assert_eq!(stats.sst_files, 0);
+        assert_eq!(stats.kvs, 6);
+        assert_eq!(stats.deleted_kvs, 1);
+        assert_eq!(stats.smallest_key, vec![0]);
+        assert_eq!(stats.largest_key, vec![3]);
+        assert_eq!(stats.commit_ts, vec![10, 20]);
+
+        engine.flush(true).unwrap();
+        let stats = engine.stats().unwrap();
+        assert_eq!(stats.approximate_kvs, 6);
+        assert_eq!(stats.sst_files, 1);
+        assert_eq!(stats.kvs, 6);
+        assert!(stats.total_size > 0);
+    }
+
+    #[test]
+    fn test_write_duplicate() {
+        let dir = TempDir::new("test_write_duplicate").unwrap();
+        let uuid = Uuid::new_v4();
+        let new_engine = || Engine::new(dir.path(), uuid, DbConfig::default(), None, true).unwrap();
+        let new_pair = |k: &[u8], v: &[u8]| {
+            let mut p = KvPair::default();
+            p.set_key(k.to_vec());
+            p.set_value(v.to_vec());
+            p
+        };
+        let new_dup = |k: &[u8], old: (&[u8], &str), new: (&[u8], &str)| DuplicateKey {
+            key: k.to_vec(),
+            commit_ts: 10,
+            old_value: Some(old.0.to_vec()),
+            old_source: old.1.to_owned(),
+            new_value: Some(new.0.to_vec()),
+            new_source: new.1.to_owned(),
+        };
+
+        let engine = new_engine();
+        let pairs = vec![new_pair(b"a", b"1"), new_pair(b"b", b"1")];
+        engine.write_v3(10, &pairs, "s1").unwrap();
+        // Same values and different commit ts are not duplicate.
+        engine.write_v3(10, &pairs, "s2").unwrap();
+        engine.write_v3(11, &[new_pair(b"a", b"2")], "s2").unwrap();
+        assert!(engine.duplicate_keys().unwrap().is_empty());
+
+        // Conflicts with the engine and within the batch.
+        let pairs = vec![
+            new_pair(b"a", b"2"),
+            new_pair(b"c", b"1"),
+            new_pair(b"c", b"2"),
+        ];
+        engine.write_v3(10, &pairs, "s3").unwrap();
+        let mut dups = vec![
+            new_dup(b"a", (b"1", "s2"), (b"2", "s3")),
+            new_dup(b"c", (b"1", "s3"), (b"2", "s3")),
+        ];
+        assert_eq!(engine.duplicate_keys().unwrap(), dups);
+        // The later value wins.
+        let key = Key::from_raw(b"a").append_ts(TimeStamp::new(10));
+        let value = engine.get(key.as_encoded()).unwrap().unwrap();
+        assert_eq!(EngineValue::decode(&value).unwrap(), EngineValue::Put(b"2"));
+
+        // Duplicate keys are kept after the engine is reopened.
+        engine.flush(true).unwrap();
+        drop(engine);
+        let engine = new_engine();
+        assert_eq!(engine.duplicate_keys().unwrap(), dups);
+        engine.write_v3(10, &[new_pair(b"b", b"2")], "s4").unwrap();
+        dups.push(new_dup(b"b", (b"1", "s2"), (b"2", "s4")));
+        assert_eq!(engine.duplicate_keys().unwrap(), dups);
+
+        // A deletion conflicts with a put.
+        let mutations = vec![(&b"c"[..], EngineValue::Delete)];
+        engine.write_mutations(10, &mutations, "s5").unwrap();
+        let mut dup = new_dup(b"c", (b"2", "s3"), (b"", "s5"));
+        dup.new_value = None;
+        dups.push(dup);
+        assert_eq!(engine.duplicate_keys().unwrap(), dups);
     }
 
     #[test]
     fn test_sst_writer() {
-        test_sst_writer_with(1, &[CF_WRITE], &SecurityManager::default());
-        test_sst_writer_with(1024, &[CF_DEFAULT, CF_WRITE], &SecurityManager::default());
+        test_sst_writer_with(1, &[CF_WRITE], None);
+        test_sst_writer_with(1024, &[CF_DEFAULT, CF_WRITE], None);
+
+        // Uploaded SSTs are decrypted even if encryption is enabled.
+        let key_dir = TempDir::new("_test_sst_writer_key").unwrap();
+        let key_manager = new_key_manager(key_dir.path());
+        test_sst_writer_with(1, &[CF_WRITE], Some(key_manager.clone()));
+        test_sst_writer_with(1024, &[CF_DEFAULT, CF_WRITE], Some(key_manager));
     }
 
-    fn test_sst_writer_with(value_size: usize, cf_names: &[&str], security_mgr: &SecurityManager) {
-        let temp_dir = TempDir::new("_test_sst_writer").unwrap();
+    #[test]
+    fn test_sst_writer_memory_budget() {
+        let temp_dir = TempDir::new("_test_sst_writer_memory_budget").unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let cfg = DbConfig::default();
+        let budget = Arc::new(SSTMemoryBudget::new(1, 0));
 
+        let new_sst_infos = || {
+            let mut w = SSTWriter::new(&cfg, None, &budget, path).unwrap();
+            w.put(&new_encoded_key(0, 10), &[0; 1024]).unwrap();
+            w.finish().unwrap()
+        };
+        let num_files = || fs::read_dir(path).unwrap().count();
+
+        // The first SSTs are built in memory and use up the budget.
+        let mem_infos = new_sst_infos();
+        assert_eq!(num_files(), 0);
+        let used: u64 = mem_infos.iter().map(|info| info.file_size).sum();
+        assert_eq!(budget.used.load(Ordering::SeqCst), used);
+
+        // The others are written to disk.
+        let disk_infos = new_sst_infos();
+        assert_eq!(num_files(), 2);
+
+        // The empty default CF file of short values is deleted.
+        let mut w = SSTWriter::new(&cfg, None, &budget, path).unwrap();
+        w.put(&new_encoded_key(0, 10), &[0; 1]).unwrap();
+        let short_infos = w.finish().unwrap();
+        assert_eq!(short_infos.len(), 1);
+        assert_eq!(num_files(), 3);
+        drop(short_infos);
+        assert_eq!(num_files(), 2);
+        for info in &disk_infos {
+            assert!(info.file_path.exists());
+            let mut data = Vec::new();
+            io::copy(&mut info.open().unwrap(), &mut data).unwrap();
+            assert_eq!(info.file_size, data.len() as u64);
+        }
+        assert_eq!(budget.used.load(Ordering::SeqCst), used);
+
+        // SSTs are deleted on drop in both modes.
+        drop(disk_infos);
+        assert_eq!(num_files(), 0);
+        drop(mem_infos);
+        assert_eq!(budget.used.load(Ordering::SeqCst), 0);
+        assert!(new_sst_infos().iter().all(|info| !info.file_path.exists()));
+    }
+
+    #[test]
+    fn test_sst_writer_reserve_budget() {
+        let budget = SSTMemoryBudget::new(2048, 1024);
+        assert!(budget.reserve());
+        assert!(budget.reserve());
+        assert!(!budget.reserve());
+        budget.release(2048);
+        // SSTs are always written to disk without budget.
+        assert!(!SSTMemoryBudget::new(0, 0).reserve());
+
+        let temp_dir = TempDir::new("_test_sst_writer_reserve_budget").unwrap();
+        let path = temp_dir.path().to_str().unwrap();
+        let cfg = DbConfig::default();
+        let budget = Arc::new(budget);
+
+        // The budget is reserved until the writer is finished.
+        let mut w = SSTWriter::new(&cfg, None, &budget, path).unwrap();
+        assert_eq!(budget.used.load(Ordering::SeqCst), 1024);
+        w.put(&new_encoded_key(0, 10), &[0; 1]).unwrap();
+        let infos = w.finish().unwrap();
+        let used: u64 = infos.iter().map(|info| info.file_size).sum();
+        assert_eq!(budget.used.load(Ordering::SeqCst), used);
+        drop(w);
+        assert_eq!(budget.used.load(Ordering::SeqCst), used);
+        drop(infos);
+        assert_eq!(budget.used.load(Ordering::SeqCst), 0);
+
+        // An unfinished writer releases the reservation on drop.
+        drop(SSTWriter::new(&cfg, None, &budget, path).unwrap());
+        assert_eq!(budget.used.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_sst_writer_short_values() {
+        let temp_dir = TempDir::new("_test_sst_writer_short_values").unwrap();
+        let path = temp_dir.path().to_str().unwrap();
         let cfg = DbConfig::default();
+        // Write SSTs to disk.
+        let budget = Arc::new(SSTMemoryBudget::new(0, 0));
+        let num_files = || fs::read_dir(path).unwrap().count();
+
+        // Files of an unfinished writer are deleted on drop.
+        let mut w = SSTWriter::new(&cfg, None, &budget, path).unwrap();
+        w.put(&new_encoded_key(0, 10), &[0; 1]).unwrap();
+        assert_eq!(num_files(), 2);
+        drop(w);
+        assert_eq!(num_files(), 0);
+
+        // Short values are written to the write CF only.
+        let mut w = SSTWriter::new(&cfg, None, &budget, path).unwrap();
+        for i in 0..10 {
+            w.put(&new_encoded_key(i, 10), &[i; 8]).unwrap();
+        }
+        let infos = w.finish().unwrap();
+        drop(w);
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].cf_name, CF_WRITE);
+        assert_eq!(num_files(), 1);
+        drop(infos);
+        assert_eq!(num_files(), 0);
+    }
+
+    fn new_test_db(path: &Path, cfg: &DbConfig) -> RocksEngine {
         let db_opts = cfg.build_opt();
         let cache = BlockCacheConfig::default().build_shared_cache();
         let cfs_opts = cfg.build_cf_opts(&cache, None, false);
-        let db = new_engine_opt(temp_dir.path().to_str().unwrap(), db_opts, cfs_opts).unwrap();
-        let db = RocksEngine::from_db(Arc::new(db));
+        let db = new_engine_opt(path.to_str().unwrap(), db_opts, cfs_opts).unwrap();
+        RocksEngine::from_db(Arc::new(db))
+    }
+
+    fn ingest_sst_infos(db: &RocksEngine, infos: &[LazySSTInfo]) {
+        for info in infos {
+            // Write the data to a file and ingest it to the engine.
+            let path = Path::new(db.path()).join("test.sst");
+            {
+                let mut src = info.open().unwrap();
+                let mut dest = File::create(&path).unwrap();
+                io::copy(&mut src, &mut dest).unwrap();
+            }
+            let mut opts = IngestExternalFileOptions::new();
+            opts.move_files(true);
+            let handle = db.as_inner().cf_handle(info.cf_name).unwrap();
+            db.as_inner()
+                .ingest_external_file_cf(handle, &opts, &[path.to_str().unwrap()])
+                .unwrap();
+        }
+    }
+
+    fn new_mvcc_reader(db: RocksEngine) -> MvccReader<RegionSnapshot<RocksSnapshot>> {
+        // Make a fake region snapshot.
+        let mut region = Region::default();
+        region.set_id(1);
+        region.mut_peers().push(Peer::default());
+        let snap = RegionSnapshot::<RocksSnapshot>::from_raw(db, region);
+        MvccReader::new(snap, None, false, IsolationLevel::Si)
+    }
+
+    fn test_sst_writer_with(
+        value_size: usize,
+        cf_names: &[&str],
+        key_manager: Option<Arc<DataKeyManager>>,
+    ) {
+        let temp_dir = TempDir::new("_test_sst_writer").unwrap();
+
+        let cfg = DbConfig::default();
+        let db = new_test_db(temp_dir.path(), &cfg);
 
         let n = 10;
         let commit_ts = 10;
-        let mut w = SSTWriter::new(&cfg, &security_mgr, temp_dir.path().to_str().unwrap()).unwrap();
+        let budget = Arc::default();
+        let mut w = SSTWriter::new(
+            &cfg,
+            key_manager,
+            &budget,
+            temp_dir.path().to_str().unwrap(),
+        )
+        .unwrap();
 
         // Write some keys.
         let value = vec![1u8; value_size];
@@ -537,28 +1250,17 @@ mod tests {
             assert_eq!(info.range.get_end(), end.as_slice());
             assert_eq!(info.cf_name, cf_name.to_owned());
 
-            // Write the data to a file and ingest it to the engine.
-            let path = Path::new(db.path()).join("test.sst");
-            {
-                let mut src = info.open().unwrap();
-                let mut dest = File::create(&path).unwrap();
-                io::copy(&mut src, &mut dest).unwrap();
-            }
-            let mut opts = IngestExternalFileOptions::new();
-            opts.move_files(true);
-            let handle = db.as_inner().cf_handle(cf_name).unwrap();
-            db.as_inner()
-                .ingest_external_file_cf(handle, &opts, &[path.to_str().unwrap()])
-                .unwrap();
+            // Check the CRC against the file content.
+            let mut data = Vec::new();
+            io::copy(&mut info.open().unwrap(), &mut data).unwrap();
+            let mut digest = crc32fast::Hasher::new();
+            digest.update(&data);
+            assert_eq!(info.compute_crc32().unwrap(), digest.finalize());
+            assert_eq!(info.file_size, data.len() as u64);
         }
+        ingest_sst_infos(&db, &infos);
 
-        // Make a fake region snapshot.
-        let mut region = Region::default();
-        region.set_id(1);
-        region.mut_peers().push(Peer::default());
-        let snap = RegionSnapshot::<RocksSnapshot>::from_raw(db, region);
-
-        let mut reader = MvccReader::new(snap, None, false, IsolationLevel::Si);
+        let mut reader = new_mvcc_reader(db);
         // Make sure that all kvs are right.
         for i in 0..n {
             let k = Key::from_raw(&[i]);
@@ -577,6 +1279,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sst_writer_with_deletes() {
+        let temp_dir = TempDir::new("_test_sst_writer_with_deletes").unwrap();
+
+        let cfg = DbConfig::default();
+        let db = new_test_db(temp_dir.path(), &cfg);
+
+        let n = 10;
+        let (put_ts, delete_ts) = (10, 20);
+        let budget = Arc::default();
+        let mut w = SSTWriter::new(&cfg, None, &budget, temp_dir.path().to_str().unwrap()).unwrap();
+
+        // Put all keys, and delete the odd ones later. Keys of the same user
+        // key are sorted by commit ts in descending order.
+        let value = vec![1u8; 1024];
+        for i in 0..n {
+            if i % 2 == 1 {
+                w.delete(&new_encoded_key(i, delete_ts)).unwrap();
+            }
+            w.put(&new_encoded_key(i, put_ts), &value).unwrap();
+        }
+
+        let infos = w.finish().unwrap();
+        assert_eq!(infos.len(), 2);
+        ingest_sst_infos(&db, &infos);
+
+        let mut reader = new_mvcc_reader(db);
+        for i in 0..n {
+            let k = Key::from_raw(&[i]);
+            // All keys are visible before the deletion.
+            let v = reader
+                .get(&k, TimeStamp::new(delete_ts - 1), None, false)
+                .unwrap();
+            assert_eq!(v.unwrap(), value);
+
+            let v = reader
+                .get(&k, TimeStamp::new(delete_ts), None, false)
+                .unwrap();
+            if i % 2 == 1 {
+                assert!(v.is_none());
+                let (commit_ts, write) = reader
+                    .seek_write(&k, TimeStamp::new(delete_ts))
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(commit_ts, TimeStamp::new(delete_ts));
+                assert_eq!(write.write_type, WriteType::Delete);
+            } else {
+                assert_eq!(v.unwrap(), value);
+            }
+        }
+    }
+
     const SIZE_INDEX_DISTANCE: usize = 4 * 1024 * 1024;
 
     #[test]
@@ -646,12 +1400,13 @@ mod tests {
                 wb.mut_mutations().push(m);
             }
             wb.set_commit_ts(i + 1);
-            engine.write(wb).unwrap();
+            engine.write(wb, "test").unwrap();
         }
         engine.flush(true).unwrap();
 
         let props = engine.get_size_properties().unwrap();
-        assert_eq!(props.total_size, 100 * 1000 * (78 + 19 + 16));
+        // Values are prefixed by the mutation type.
+        assert_eq!(props.total_size, 100 * 1000 * (79 + 19 + 16));
 
         let mut cur_size = 0;
         for (_, v) in props.index_handles.iter() {
@@ -8,6 +8,7 @@ use std::result;
 use grpcio::Error as GrpcError;
 use kvproto::errorpb;
 use kvproto::metapb::*;
+use kvproto::pdpb;
 use thiserror::Error;
 use uuid::{self, Uuid};
 
@@ -46,24 +47,42 @@ pub enum Error {
     InvalidProtoMessage(String),
     #[error("Invalid chunk")]
     InvalidChunk,
+    #[error("Invalid engine value")]
+    InvalidEngineValue,
     #[error("{0}")]
     PdRPC(#[from] PdError),
     #[error("TikvRPC {0:?}")]
     TikvRPC(errorpb::Error),
+    #[error("PdRPC {0:?}")]
+    PdResponse(pdpb::Error),
     #[error("NotLeader, leader may {0:?}")]
     NotLeader(Option<Peer>),
     #[error("EpochNotMatch")]
     EpochNotMatch(Vec<Region>),
+    #[error("ServerIsBusy {reason}, backoff {backoff_ms}ms")]
+    ServerIsBusy { reason: String, backoff_ms: u64 },
+    #[error("RegionNotFound {0}")]
+    RegionNotFound(u64),
+    #[error("SST is outside region {0}")]
+    SSTOutsideRegion(u64),
+    #[error("KeyNotInRegion region {region_id}")]
+    KeyNotInRegion { key: Vec<u8>, region_id: u64 },
+    #[error("StaleCommand")]
+    StaleCommand,
     #[error("UpdateRegion")]
     UpdateRegion(RegionInfo),
     #[error("{0}")]
     ImportJobFailed(String),
+    #[error("Import job of engine {0} is aborted")]
+    ImportJobAborted(Uuid),
     #[error("{0}")]
     ImportSSTJobFailed(String),
     #[error("{0}")]
     PrepareRangeJobFailed(String),
     #[error("{0}")]
     ResourceTemporarilyUnavailable(String),
+    #[error("Invalid PD address {0:?}")]
+    InvalidPdAddr(String),
     #[error("{0}")]
     Security(String),
 }
@@ -88,12 +107,60 @@ impl From<errorpb::Error> for Error {
         } else if err.has_epoch_not_match() {
             let mut error = err.take_epoch_not_match();
             Error::EpochNotMatch(error.take_current_regions().to_vec())
+        } else if err.has_server_is_busy() {
+            let mut error = err.take_server_is_busy();
+            Error::ServerIsBusy {
+                reason: error.take_reason(),
+                backoff_ms: error.get_backoff_ms(),
+            }
+        } else if err.has_region_not_found() {
+            Error::RegionNotFound(err.get_region_not_found().get_region_id())
+        } else if err.has_key_not_in_region() {
+            let mut error = err.take_key_not_in_region();
+            Error::KeyNotInRegion {
+                key: error.take_key(),
+                region_id: error.get_region_id(),
+            }
+        } else if err.has_stale_command() {
+            Error::StaleCommand
         } else {
             Error::TikvRPC(err)
         }
     }
 }
 
+#[test]
+fn test_from_region_error() {
+    let mut err = errorpb::Error::default();
+    err.mut_server_is_busy().set_reason("busy".to_owned());
+    err.mut_server_is_busy().set_backoff_ms(10);
+    match Error::from(err) {
+        Error::ServerIsBusy { reason, backoff_ms } => {
+            assert_eq!(reason, "busy");
+            assert_eq!(backoff_ms, 10);
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+
+    let mut err = errorpb::Error::default();
+    err.mut_region_not_found().set_region_id(1);
+    assert!(matches!(Error::from(err), Error::RegionNotFound(1)));
+
+    let mut err = errorpb::Error::default();
+    err.mut_key_not_in_region().set_region_id(2);
+    assert!(matches!(
+        Error::from(err),
+        Error::KeyNotInRegion { region_id: 2, .. }
+    ));
+
+    let mut err = errorpb::Error::default();
+    err.mut_stale_command();
+    assert!(matches!(Error::from(err), Error::StaleCommand));
+
+    let err = errorpb::Error::default();
+    assert!(matches!(Error::from(err), Error::TikvRPC(_)));
+}
+
 #[test]
 fn test_description() {
     assert_eq!(
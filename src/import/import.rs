@@ -1,5 +1,6 @@
 // Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::collections::VecDeque;
 use std::future::Future;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
@@ -16,47 +17,85 @@ use futures_timer::Delay;
 use kvproto::import_sstpb::*;
 use uuid::Uuid;
 
+use collections::HashSet;
 use pd_client::RegionInfo;
-use tikv_util::time::{Instant, Limiter};
+use tikv_util::time::Instant;
 
 use super::client::*;
 use super::common::*;
 use super::engine::*;
+use super::limiter::UploadLimiter;
+use super::manifest::ImportCheckpoint;
 use super::metrics::*;
 use super::prepare::*;
+use super::progress::*;
 use super::stream::*;
-use super::{Config, Error, Result};
-
-const MAX_RETRY_TIMES: u64 = 5;
-const RETRY_INTERVAL_SECS: u64 = 3;
-const STORE_UNAVAILABLE_WAIT_INTERVAL_MILLIS: u64 = 20000;
+use super::{Config, Error, Result, RetryConfig};
 
 /// ImportJob is responsible for importing data stored in an engine to a cluster.
+///
+/// Ranges which have been imported are recorded in the checkpoint, so that
+/// they can be skipped if the job is run again after a failure.
 pub struct ImportJob<Client> {
     tag: String,
     cfg: Config,
     client: Client,
     engine: Arc<Engine>,
+    checkpoint: Arc<ImportCheckpoint>,
+    progress: Arc<ImportProgress>,
     counter: Arc<AtomicUsize>,
-    speed_limit: Limiter,
+    limiter: Arc<UploadLimiter>,
 }
 
 impl<Client: ImportClient> ImportJob<Client> {
-    pub fn new(cfg: Config, client: Client, engine: Engine) -> ImportJob<Client> {
-        let speed_limit = Limiter::new(cfg.upload_speed_limit.0 as f64);
+    pub fn new(
+        cfg: Config,
+        client: Client,
+        engine: Engine,
+        checkpoint: ImportCheckpoint,
+        limiter: Arc<UploadLimiter>,
+    ) -> ImportJob<Client> {
         ImportJob {
             tag: format!("[ImportJob {}]", engine.uuid()),
             cfg,
             client,
+            progress: Arc::new(ImportProgress::new(engine.uuid())),
             engine: Arc::new(engine),
+            checkpoint: Arc::new(checkpoint),
             counter: Arc::new(AtomicUsize::new(1)),
-            speed_limit,
+            limiter,
         }
     }
 
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub fn progress(&self) -> &ImportProgress {
+        &self.progress
+    }
+
+    /// Aborts the job, running sub jobs stop as soon as possible and
+    /// pending ranges are dropped.
+    pub fn abort(&self) {
+        info!("abort import engine"; "tag" => %self.tag);
+        self.progress.abort();
+    }
+
     pub async fn run(&self) -> Result<()> {
+        let res = self.run_import().await;
+        self.progress.finish(&res);
+        res
+    }
+
+    async fn run_import(&self) -> Result<()> {
         let start = Instant::now();
-        info!("import engine"; "tag" => %self.tag);
+        let num_finished_ranges = self.checkpoint.finished_ranges().len();
+        info!("import engine"; "tag" => %self.tag, "finished_ranges" => %num_finished_ranges);
 
         // Join and check results.
         let mut res = Ok(());
@@ -65,6 +104,7 @@ impl<Client: ImportClient> ImportJob<Client> {
             self.cfg.clone(),
             self.client.clone(),
             Arc::clone(&self.engine),
+            Arc::clone(&self.progress),
         );
 
         let mut ranges = job
@@ -74,6 +114,7 @@ impl<Client: ImportClient> ImportJob<Client> {
             .map(|range| range.range)
             .collect();
         IMPORT_EACH_PHASE.with_label_values(&["import"]).set(1.0);
+        self.progress.set_phase(ImportPhase::Import);
 
         let import_thread_pool = ThreadPoolBuilder::new()
             .name_prefix("import-job-")
@@ -86,7 +127,9 @@ impl<Client: ImportClient> ImportJob<Client> {
             .create()
             .unwrap();
 
-        for i in 0..MAX_RETRY_TIMES {
+        let max_retry_times = self.cfg.retry.max_retry_times;
+        for i in 0..max_retry_times {
+            self.progress.reset_retrying_ranges();
             let retry_ranges = Arc::new(Mutex::new(Vec::new()));
             let handles = self
                 .run_import_threads(
@@ -99,6 +142,10 @@ impl<Client: ImportClient> ImportJob<Client> {
             if let Some(e) = handles.filter_map(|x| future::ready(x.err())).next().await {
                 res = Err(e)
             }
+            if self.progress.is_aborted() {
+                res = Err(Error::ImportJobAborted(self.engine.uuid()));
+                break;
+            }
             ranges = Arc::try_unwrap(retry_ranges).unwrap().into_inner().unwrap();
             let retry_count = ranges.len();
             if retry_count < 1 {
@@ -110,10 +157,10 @@ impl<Client: ImportClient> ImportJob<Client> {
                 "retry_count" => %retry_count,
                 "current_round" => %i,
             );
-            if i == MAX_RETRY_TIMES - 1 {
+            if i == max_retry_times - 1 {
                 res = Err(Error::ImportJobFailed(format!(
                     "retry {} times still {} ranges failed",
-                    MAX_RETRY_TIMES, retry_count
+                    max_retry_times, retry_count
                 )))
             }
         }
@@ -122,6 +169,11 @@ impl<Client: ImportClient> ImportJob<Client> {
         match res {
             Ok(_) => {
                 info!("import engine completed"; "tag" => %self.tag, "takes" => ?start.elapsed());
+                // The engine is imported completely, the next import should
+                // start from scratch.
+                if let Err(e) = self.checkpoint.reset() {
+                    warn!("reset import checkpoint failed"; "tag" => %self.tag, "err" => %e);
+                }
                 Ok(())
             }
             Err(e) => {
@@ -140,11 +192,16 @@ impl<Client: ImportClient> ImportJob<Client> {
     ) -> impl Future<Output = Result<()>> + 'static {
         let client = self.client.clone();
         let engine = Arc::clone(&self.engine);
+        let checkpoint = Arc::clone(&self.checkpoint);
+        let progress = Arc::clone(&self.progress);
         let counter = Arc::clone(&self.counter);
-        let speed_limit = self.speed_limit.clone();
+        let limiter = Arc::clone(&self.limiter);
+        let retry = self.cfg.retry.clone();
 
         async move {
-            let job = SubImportJob::new(id, rx, client, engine, counter, speed_limit);
+            let job = SubImportJob::new(
+                id, rx, client, engine, checkpoint, progress, counter, limiter, retry,
+            );
             job.run_sub_import_job(retry_ranges).await
         }
     }
@@ -155,9 +212,10 @@ impl<Client: ImportClient> ImportJob<Client> {
         client: Arc<Client>,
         range_rx: Receiver<Range>,
         sst_tx: Sender<LazySSTRange>,
-        finished_ranges: Arc<Mutex<Vec<Range>>>,
     ) -> impl Future<Output = Result<()>> + 'static {
         let engine = Arc::clone(&self.engine);
+        let checkpoint = Arc::clone(&self.checkpoint);
+        let progress = Arc::clone(&self.progress);
         let cfg = self.cfg.clone();
         let tag = self.tag.clone();
 
@@ -165,8 +223,12 @@ impl<Client: ImportClient> ImportJob<Client> {
             let mut ranges_handled = 0;
 
             'NEXT_RANGE: while let Ok(range) = range_rx.recv().await {
+                if progress.is_aborted() {
+                    // Drain the pending ranges.
+                    continue;
+                }
                 ranges_handled += 1;
-                'RETRY: for _ in 0..MAX_RETRY_TIMES {
+                'RETRY: for _ in 0..cfg.retry.max_retry_times {
                     let cfg = cfg.clone();
                     let client = Arc::clone(&client);
                     let engine = Arc::clone(&engine);
@@ -175,10 +237,13 @@ impl<Client: ImportClient> ImportJob<Client> {
                         client,
                         engine,
                         range.clone(),
-                        finished_ranges.lock().unwrap().clone(),
+                        checkpoint.finished_ranges(),
                     );
 
                     loop {
+                        if progress.is_aborted() {
+                            continue 'NEXT_RANGE;
+                        }
                         let split_start = Instant::now_coarse();
                         match stream.next().await {
                             Ok(Some(info)) => {
@@ -215,7 +280,6 @@ impl<Client: ImportClient> ImportJob<Client> {
         info!("run import threads"; "tag" => %self.tag, "num_import_jobs" => %self.cfg.num_import_jobs);
 
         let handles = FuturesUnordered::new();
-        let finished_ranges = Arc::new(Mutex::new(Vec::new()));
         let (sst_tx, sst_rx) = bounded(self.cfg.num_import_jobs);
 
         // Spawn a group of threads to execute real import jobs
@@ -244,13 +308,15 @@ impl<Client: ImportClient> ImportJob<Client> {
                         Arc::clone(&client),
                         range_rx.clone(),
                         sst_tx.clone(),
-                        Arc::clone(&finished_ranges),
                     ))
                     .unwrap(),
             );
         }
 
         for range in ranges {
+            if self.progress.is_aborted() {
+                break;
+            }
             let start = Instant::now_coarse();
             range_tx.send(range).await.unwrap();
             IMPORT_RANGE_DELIVERY_DURATION.observe(start.elapsed_secs());
@@ -267,28 +333,38 @@ struct SubImportJob<Client> {
     rx: Receiver<LazySSTRange>,
     client: Arc<Client>,
     engine: Arc<Engine>,
+    checkpoint: Arc<ImportCheckpoint>,
+    progress: Arc<ImportProgress>,
     counter: Arc<AtomicUsize>,
     num_errors: Arc<AtomicUsize>,
-    speed_limit: Limiter,
+    limiter: Arc<UploadLimiter>,
+    retry: RetryConfig,
 }
 
 impl<Client: ImportClient> SubImportJob<Client> {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         id: u64,
         rx: Receiver<LazySSTRange>,
         client: Client,
         engine: Arc<Engine>,
+        checkpoint: Arc<ImportCheckpoint>,
+        progress: Arc<ImportProgress>,
         counter: Arc<AtomicUsize>,
-        speed_limit: Limiter,
+        limiter: Arc<UploadLimiter>,
+        retry: RetryConfig,
     ) -> SubImportJob<Client> {
         SubImportJob {
             id,
             rx,
             client: Arc::new(client),
             engine,
+            checkpoint,
+            progress,
             counter,
             num_errors: Arc::new(AtomicUsize::new(0)),
-            speed_limit,
+            limiter,
+            retry,
         }
     }
 
@@ -303,27 +379,114 @@ impl<Client: ImportClient> SubImportJob<Client> {
         while let Ok((range, ssts)) = self.rx.recv().await {
             IMPORT_SST_RECV_DURATION.observe(start.elapsed_secs());
             start = Instant::now_coarse();
-            'NEXT_SST: for lazy_sst in ssts {
-                let sst = lazy_sst.into_sst_file()?;
+            if self.progress.is_aborted() {
+                // Drain the pending ssts, the files are deleted on drop.
+                continue;
+            }
+            let mut failed = false;
+            let mut resplits = 0;
+            // SSTs are imported in order, so that the default CF is always
+            // ingested before the write CF referring to it.
+            let mut pending: VecDeque<LazySSTInfo> = ssts.into();
+            while let Some(info) = pending.pop_front() {
+                // The CRC is only computed for ssts to upload.
+                let sst = match info.into_sst_file() {
+                    Ok(sst) => sst,
+                    Err(e) => {
+                        warn!("read sst failed"; "id" => %sub_id, "err" => %e);
+                        failed = true;
+                        break;
+                    }
+                };
+                let sst_range = sst.meta.get_range().clone();
+                let cf_name = sst.meta.get_cf_name().to_owned();
                 let id = counter.fetch_add(1, Ordering::SeqCst);
                 let tag = format!("[ImportSSTJob {}:{}:{}]", engine.uuid(), sub_id, id);
                 let res = {
-                    ImportSSTJob::new(tag, sst, Arc::clone(&client), &self.speed_limit)
-                        .run_import_sst_job()
-                        .await
+                    ImportSSTJob::new(
+                        tag,
+                        sst,
+                        Arc::clone(&client),
+                        &self.progress,
+                        &self.limiter,
+                        &self.retry,
+                    )
+                    .run_import_sst_job()
+                    .await
                 };
-                // Entire range will be retried if any sst in this range failed,
-                // so there is no need for retry single sst
-                if res.is_err() {
-                    num_errors.fetch_add(1, Ordering::SeqCst);
-                    retry_ranges.lock().unwrap().push(range);
-                    break 'NEXT_SST;
+                match res {
+                    Ok(_) => {}
+                    // The region has been split, only the pieces of this sst
+                    // need to be imported again.
+                    Err(Error::SSTOutsideRegion(_)) if resplits < self.retry.max_retry_times => {
+                        resplits += 1;
+                        match self.resplit_sst(&sst_range, &cf_name).await {
+                            // The pieces take the place of the sst.
+                            Ok(ssts) => {
+                                for sst in ssts.into_iter().rev() {
+                                    pending.push_front(sst);
+                                }
+                            }
+                            Err(e) => {
+                                warn!("resplit sst failed"; "id" => %sub_id, "err" => %e);
+                                failed = true;
+                                break;
+                            }
+                        }
+                    }
+                    // Entire range will be retried if any sst in this range failed,
+                    // so there is no need for retry single sst
+                    Err(_) => {
+                        failed = true;
+                        break;
+                    }
                 }
             }
+            if failed {
+                if self.progress.is_aborted() {
+                    continue;
+                }
+                num_errors.fetch_add(1, Ordering::SeqCst);
+                retry_ranges.lock().unwrap().push(range);
+                self.progress.retry_range();
+            } else {
+                if let Err(e) = self.checkpoint.add(range) {
+                    warn!("save import checkpoint failed"; "id" => %sub_id, "err" => %e);
+                }
+                self.progress.finish_range();
+            }
         }
 
         Ok(())
     }
+
+    /// Generates new ssts of `cf_name` from the engine for the sst `range`,
+    /// split along the current region boundaries.
+    async fn resplit_sst(&self, range: &Range, cf_name: &str) -> Result<Vec<LazySSTInfo>> {
+        // The end of an sst range is inclusive.
+        let mut end_key = range.get_end().to_owned();
+        end_key.push(0);
+
+        let mut ssts = Vec::new();
+        let mut start = range.get_start().to_owned();
+        while start < end_key {
+            let region = self.client.get_region(&start).await?;
+            let region_end = region.get_end_key();
+            let end = if region_end.is_empty() || end_key.as_slice() <= region_end {
+                end_key.clone()
+            } else {
+                region_end.to_owned()
+            };
+            let piece = new_range(&start, &end);
+            if let Some(info) = new_sst_for_range(&self.engine, piece, cf_name)? {
+                ssts.push(info);
+            }
+            start = end;
+        }
+
+        info!("resplit sst"; "id" => %self.id, "range" => ?ReadableDebug(range), "ssts" => %ssts.len());
+        Ok(ssts)
+    }
 }
 
 /// ImportSSTJob is responsible for importing `sst` to all replicas of the
@@ -332,16 +495,30 @@ struct ImportSSTJob<'a, Client> {
     tag: String,
     sst: SSTFile,
     client: Arc<Client>,
-    speed_limit: &'a Limiter,
+    progress: &'a ImportProgress,
+    limiter: &'a UploadLimiter,
+    retry: &'a RetryConfig,
+    // Stores which the sst with the current uuid has been uploaded to.
+    uploaded_stores: HashSet<u64>,
 }
 
 impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
-    fn new(tag: String, sst: SSTFile, client: Arc<Client>, speed_limit: &'a Limiter) -> Self {
+    fn new(
+        tag: String,
+        sst: SSTFile,
+        client: Arc<Client>,
+        progress: &'a ImportProgress,
+        limiter: &'a UploadLimiter,
+        retry: &'a RetryConfig,
+    ) -> Self {
         ImportSSTJob {
             tag,
             sst,
             client,
-            speed_limit,
+            progress,
+            limiter,
+            retry,
+            uploaded_stores: HashSet::default(),
         }
     }
 
@@ -349,9 +526,13 @@ impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
         let start = Instant::now();
         info!("import sst"; "tag" => %self.tag, "sst" => ?self.sst);
 
-        for i in 0..MAX_RETRY_TIMES {
+        for i in 0..self.retry.max_retry_times {
             if i != 0 {
-                Delay::new(Duration::from_secs(RETRY_INTERVAL_SECS)).await;
+                Delay::new(self.retry.backoff(i)).await;
+            }
+            if self.progress.is_aborted() {
+                warn!("import sst aborted"; "tag" => %self.tag);
+                return Err(Error::ImportSSTJobFailed(self.tag.clone()));
             }
 
             let range = self.sst.meta.get_range().clone();
@@ -361,7 +542,7 @@ impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
                         region
                     } else {
                         warn!("sst out of region range"; "tag" => %self.tag, "region" => ?ReadableDebug(&region));
-                        return Err(Error::ImportSSTJobFailed(self.tag.clone()));
+                        return Err(Error::SSTOutsideRegion(region.get_id()));
                     }
                 }
                 Err(e) => {
@@ -370,7 +551,7 @@ impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
                 }
             };
 
-            for _ in 0..MAX_RETRY_TIMES {
+            for _ in 0..self.retry.max_retry_times {
                 match self.import(region).await {
                     Ok(_) => {
                         info!("import sst completed"; "tag" => %self.tag, "takes" => ?start.elapsed());
@@ -380,6 +561,8 @@ impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
                         region = new_region;
                         continue;
                     }
+                    // The sst needs to be split along the new regions.
+                    Err(e @ Error::SSTOutsideRegion(_)) => return Err(e),
                     Err(_) => break,
                 }
             }
@@ -395,10 +578,18 @@ impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
         // Update SST meta for this region.
         {
             let meta = &mut self.sst.meta;
-            // Uuid can not be reused, we must generate a new uuid here.
-            meta.set_uuid(Uuid::new_v4().as_bytes().to_vec());
-            meta.set_region_id(region.get_id());
-            meta.set_region_epoch(region.get_region_epoch().clone());
+            // If the region is not changed, the uploaded files can be ingested
+            // again, so we only need to upload to the rest stores. Otherwise,
+            // uuid can not be reused, we must generate a new uuid here.
+            if self.uploaded_stores.is_empty()
+                || meta.get_region_id() != region.get_id()
+                || meta.get_region_epoch() != region.get_region_epoch()
+            {
+                meta.set_uuid(Uuid::new_v4().as_bytes().to_vec());
+                meta.set_region_id(region.get_id());
+                meta.set_region_epoch(region.get_region_epoch().clone());
+                self.uploaded_stores.clear();
+            }
         }
 
         let start = Instant::now_coarse();
@@ -433,44 +624,56 @@ impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
                     }
                     None => {
                         warn!("epoch not match"; "tag" => %self.tag, "new_regions" => ?current_regions);
-                        Err(Error::EpochNotMatch(current_regions))
+                        Err(Error::SSTOutsideRegion(region.get_id()))
                     }
                 }
             }
-            Err(e) => Err(e),
+            // The uploaded files can be ingested to the same region again.
+            Err(Error::ServerIsBusy { .. }) | Err(Error::StaleCommand) => {
+                Err(Error::UpdateRegion(region))
+            }
+            Err(e) => {
+                // We don't know whether the uploaded files are still usable,
+                // upload them again with a new uuid.
+                self.uploaded_stores.clear();
+                Err(e)
+            }
         }
     }
 
-    async fn upload(&self, region: &RegionInfo) -> Result<()> {
+    /// Uploads the sst to all peers of the region concurrently, peers which
+    /// have been uploaded are skipped.
+    async fn upload(&mut self, region: &RegionInfo) -> Result<()> {
         let start = Instant::now();
         let size = self.sst.info.file_size;
 
-        for peer in region.get_peers() {
-            self.speed_limit.consume(size as usize).await;
-
-            let file = self.sst.info.open()?;
-            let upload = UploadStream::new(self.sst.meta.clone(), file);
-            let store_id = peer.get_store_id();
-            let mut err_logged = false;
-            while !self.client.is_space_enough(store_id, size).await? {
-                if !err_logged {
-                    warn!("no enough space, will retry silently"; "tag" => %self.tag, "store" => %store_id, "size" => %size);
-                    err_logged = true;
+        let stores: Vec<u64> = region
+            .get_peers()
+            .iter()
+            .map(|p| p.get_store_id())
+            .filter(|id| !self.uploaded_stores.contains(id))
+            .collect();
+        let results = future::join_all(
+            stores
+                .iter()
+                .map(|&store_id| self.upload_to_store(store_id)),
+        )
+        .await;
+
+        let mut res = Ok(());
+        for (store_id, r) in stores.into_iter().zip(results) {
+            match r {
+                Ok(_) => {
+                    self.uploaded_stores.insert(store_id);
+                    self.progress.add_uploaded_bytes(size);
+                }
+                Err(e) => {
+                    warn!("upload failed"; "tag" => %self.tag, "store" => %store_id, "region_id" => %region.id, "err" => %e);
+                    res = Err(e);
                 }
-                let label = format!("{}", store_id);
-                IMPORT_STORE_SAPCE_NOT_ENOUGH_COUNTER
-                    .with_label_values(&[label.as_str()])
-                    .inc();
-                Delay::new(Duration::from_millis(
-                    STORE_UNAVAILABLE_WAIT_INTERVAL_MILLIS,
-                ))
-                .await;
-            }
-            if let Err(e) = self.client.upload_sst(store_id, upload).await {
-                warn!("upload failed"; "tag" => %self.tag, "store" => %store_id, "err" => %e);
-                return Err(e);
             }
         }
+        res?;
 
         let takes = start.elapsed();
         if takes > Duration::from_secs(1) {
@@ -480,6 +683,28 @@ impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
         Ok(())
     }
 
+    async fn upload_to_store(&self, store_id: u64) -> Result<()> {
+        let size = self.sst.info.file_size;
+        let mut err_logged = false;
+        while !self.client.is_space_enough(store_id, size).await? {
+            if !err_logged {
+                warn!("no enough space, will retry silently"; "tag" => %self.tag, "store" => %store_id, "size" => %size);
+                err_logged = true;
+            }
+            let label = format!("{}", store_id);
+            IMPORT_STORE_SAPCE_NOT_ENOUGH_COUNTER
+                .with_label_values(&[label.as_str()])
+                .inc();
+            Delay::new(self.retry.store_unavailable_wait_interval.0).await;
+        }
+
+        let _permit = self.limiter.acquire_upload(store_id, size).await;
+        let file = self.sst.info.open()?;
+        let upload = UploadStream::new(self.sst.meta.clone(), file);
+        self.client.upload_sst(store_id, upload).await?;
+        Ok(())
+    }
+
     async fn ingest(&self, region: &RegionInfo) -> Result<()> {
         let start = Instant::now();
 
@@ -490,13 +715,22 @@ impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
         ingest.set_context(ctx);
         ingest.set_sst(self.sst.meta.clone());
 
-        let res = match self.client.ingest_sst(store_id, ingest).await {
+        let permit = self.limiter.acquire_ingest(store_id).await;
+        let res = self.client.ingest_sst(store_id, ingest).await;
+        drop(permit);
+
+        let res = match res {
             Ok(mut resp) => {
                 if !resp.has_error() {
                     Ok(())
                 } else {
                     match Error::from(resp.take_error()) {
                         e @ Error::NotLeader(_) | e @ Error::EpochNotMatch(_) => return Err(e),
+                        Error::ServerIsBusy { reason, backoff_ms } => {
+                            let backoff = self.limiter.on_server_busy(store_id, backoff_ms);
+                            Delay::new(backoff).await;
+                            Err(Error::ServerIsBusy { reason, backoff_ms })
+                        }
                         e => Err(e),
                     }
                 }
@@ -506,6 +740,7 @@ impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
 
         match res {
             Ok(_) => {
+                self.limiter.on_success(store_id);
                 info!("ingest completed"; "tag" => %self.tag, "takes" => ?start.elapsed(), "store" => %store_id, "region_id" => %region.id);
                 Ok(())
             }
@@ -516,3 +751,85 @@ impl<'a, Client: ImportClient> ImportSSTJob<'a, Client> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::test_helpers::*;
+
+    use futures::executor::block_on;
+    use tempdir::TempDir;
+
+    use engine_traits::{CF_DEFAULT, CF_WRITE};
+    use tikv::config::DbConfig;
+    use txn_types::{Key, TimeStamp};
+
+    fn new_encoded_key(i: u8) -> Vec<u8> {
+        Key::from_raw(&[i])
+            .append_ts(TimeStamp::zero())
+            .into_encoded()
+    }
+
+    #[test]
+    fn test_import_sst_resplit() {
+        let dir = TempDir::new("test_import_sst_resplit").unwrap();
+        let uuid = Uuid::new_v4();
+        let db_cfg = DbConfig::default();
+        let engine = Arc::new(Engine::new(dir.path(), uuid, db_cfg, None, false).unwrap());
+
+        // Long values are stored in both default CF and write CF.
+        let value = vec![1u8; 1024];
+        let mut w = engine.new_sst_writer().unwrap();
+        for i in 0..4 {
+            let k = new_encoded_key(i);
+            engine.put(&k, &EngineValue::Put(&value).encode()).unwrap();
+            w.put(&k, &value).unwrap();
+        }
+        let ssts = w.finish().unwrap();
+
+        // The region has been split since the ssts were generated.
+        let split_key = Key::from_raw(&[2]).into_encoded();
+        let mut client = MockClient::new();
+        client.add_region_range(b"", &split_key);
+        client.add_region_range(&split_key, b"");
+
+        let (tx, rx) = bounded(1);
+        block_on(tx.send((Range::default(), ssts))).unwrap();
+        drop(tx);
+
+        let checkpoint = ImportCheckpoint::load(dir.path().join("checkpoint"), 1).unwrap();
+        let job = SubImportJob::new(
+            0,
+            rx,
+            client.clone(),
+            engine,
+            Arc::new(checkpoint),
+            Arc::new(ImportProgress::new(uuid)),
+            Arc::new(AtomicUsize::new(1)),
+            Arc::new(UploadLimiter::new(&Config::default())),
+            RetryConfig::default(),
+        );
+        let retry_ranges = Arc::new(Mutex::new(Vec::new()));
+        block_on(job.run_sub_import_job(Arc::clone(&retry_ranges))).unwrap();
+        assert!(retry_ranges.lock().unwrap().is_empty());
+
+        // Pieces of the default CF sst are ingested before the write CF.
+        let ingested: Vec<_> = client
+            .get_ingested_ssts()
+            .iter()
+            .map(|sst| (sst.get_cf_name().to_owned(), sst.get_range().clone()))
+            .collect();
+        let expected = vec![
+            (CF_DEFAULT, 0, 1),
+            (CF_DEFAULT, 2, 3),
+            (CF_WRITE, 0, 1),
+            (CF_WRITE, 2, 3),
+        ];
+        assert_eq!(ingested.len(), expected.len());
+        for ((cf_name, range), (expected_cf, start, end)) in ingested.iter().zip(expected) {
+            assert_eq!(cf_name.as_str(), expected_cf);
+            assert_eq!(range.get_start(), new_encoded_key(start).as_slice());
+            assert_eq!(range.get_end(), new_encoded_key(end).as_slice());
+        }
+    }
+}
@@ -0,0 +1,11 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Messages and gRPC bindings of `proto/import_kv_extpb.proto`, which are
+//! generated by the build script.
+//!
+//! kvproto can't be extended by the importer alone, so requests which are
+//! only served by the importer are defined in the proto file of the importer.
+
+#![allow(clippy::all)]
+
+include!(concat!(env!("OUT_DIR"), "/import_kv_extpb.rs"));
@@ -0,0 +1,219 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use futures::pin_mut;
+use futures_timer::Delay;
+use kvproto::import_sstpb::{SwitchMode, SwitchModeRequest};
+
+use collections::{HashMap, HashSet};
+use tikv_util::time::Instant;
+
+use super::client::Client;
+use super::Config;
+
+struct ClusterState {
+    running_jobs: usize,
+    last_renew: Instant,
+}
+
+/// ImportModeKeeper keeps the stores of a cluster in import mode while any
+/// import job of the cluster is running.
+///
+/// TiKV switches back to normal mode by itself if import mode is not renewed
+/// in time, so the keeper renews it periodically, and switches the stores
+/// back to normal mode once the last job of the cluster finishes, unless
+/// import mode of the cluster has been requested explicitly.
+pub struct ImportModeKeeper {
+    renew_interval: Duration,
+    strict: bool,
+    // Clusters with running import jobs, indexed by the cluster id.
+    clusters: Mutex<HashMap<u64, ClusterState>>,
+    // Clusters switched to import mode by SwitchMode requests.
+    explicit_clusters: Mutex<HashSet<u64>>,
+}
+
+impl ImportModeKeeper {
+    pub fn new(cfg: &Config) -> ImportModeKeeper {
+        ImportModeKeeper {
+            renew_interval: cfg.import_mode_renew_interval.0,
+            strict: cfg.strict_cluster_request,
+            clusters: Mutex::new(HashMap::default()),
+            explicit_clusters: Mutex::new(HashSet::default()),
+        }
+    }
+
+    /// Records the mode of the cluster requested explicitly, import mode
+    /// requested explicitly is not switched back when import jobs finish.
+    pub fn set_explicit_mode(&self, cluster_id: u64, mode: SwitchMode) {
+        let mut clusters = self.explicit_clusters.lock().unwrap();
+        if mode == SwitchMode::Import {
+            clusters.insert(cluster_id);
+        } else {
+            clusters.remove(&cluster_id);
+        }
+    }
+
+    /// Runs `job` and keeps the cluster of `client` in import mode until all
+    /// running jobs of the cluster finish.
+    pub async fn run<F: Future>(&self, client: &Client, job: F) -> F::Output {
+        if self.renew_interval == Duration::from_secs(0) {
+            return job.await;
+        }
+        let cluster_id = match client.cluster_id() {
+            Ok(id) => id,
+            Err(e) => {
+                warn!("get cluster id failed, skip keeping import mode"; "err" => %e);
+                return job.await;
+            }
+        };
+
+        let guard = self.enter(cluster_id);
+        self.switch_mode(client, cluster_id, SwitchMode::Import)
+            .await;
+        let renew = async {
+            loop {
+                Delay::new(self.renew_interval).await;
+                if self.should_renew(cluster_id) {
+                    self.switch_mode(client, cluster_id, SwitchMode::Import)
+                        .await;
+                }
+            }
+        };
+
+        pin_mut!(job);
+        pin_mut!(renew);
+        let output = match future::select(job, renew).await {
+            Either::Left((output, _)) => output,
+            Either::Right(_) => unreachable!(),
+        };
+        if guard.leave() {
+            self.switch_mode(client, cluster_id, SwitchMode::Normal)
+                .await;
+        }
+        output
+    }
+
+    async fn switch_mode(&self, client: &Client, cluster_id: u64, mode: SwitchMode) {
+        let mut req = SwitchModeRequest::default();
+        req.set_mode(mode);
+        match client.switch_cluster(&req, self.strict).await {
+            Ok(_) => info!("switch cluster mode"; "cluster_id" => %cluster_id, "mode" => ?mode),
+            Err(e) => {
+                warn!("switch cluster mode failed"; "cluster_id" => %cluster_id, "mode" => ?mode, "err" => %e)
+            }
+        }
+    }
+
+    fn enter(&self, cluster_id: u64) -> JobGuard<'_> {
+        let mut clusters = self.clusters.lock().unwrap();
+        let state = clusters.entry(cluster_id).or_insert_with(|| ClusterState {
+            running_jobs: 0,
+            last_renew: Instant::now_coarse(),
+        });
+        state.running_jobs += 1;
+        state.last_renew = Instant::now_coarse();
+        JobGuard {
+            keeper: self,
+            cluster_id,
+            left: false,
+        }
+    }
+
+    /// Returns true if it is the last running job of the cluster, and the
+    /// cluster is not kept in import mode explicitly.
+    fn leave(&self, cluster_id: u64) -> bool {
+        let mut clusters = self.clusters.lock().unwrap();
+        let state = clusters.get_mut(&cluster_id).unwrap();
+        state.running_jobs -= 1;
+        if state.running_jobs == 0 {
+            clusters.remove(&cluster_id);
+            return !self.explicit_clusters.lock().unwrap().contains(&cluster_id);
+        }
+        false
+    }
+
+    /// Returns true if import mode of the cluster should be renewed, only
+    /// one of the running jobs renews it in each interval.
+    fn should_renew(&self, cluster_id: u64) -> bool {
+        let mut clusters = self.clusters.lock().unwrap();
+        let state = clusters.get_mut(&cluster_id).unwrap();
+        let now = Instant::now_coarse();
+        if now.duration_since(state.last_renew) < self.renew_interval {
+            return false;
+        }
+        state.last_renew = now;
+        true
+    }
+}
+
+/// JobGuard leaves the cluster when the job finishes, or when the job is
+/// dropped or panics. The stores are not switched back to normal mode in the
+/// latter case, TiKV does it by itself once import mode is not renewed.
+struct JobGuard<'a> {
+    keeper: &'a ImportModeKeeper,
+    cluster_id: u64,
+    left: bool,
+}
+
+impl JobGuard<'_> {
+    /// Returns true if the cluster should be switched back to normal mode.
+    fn leave(mut self) -> bool {
+        self.left = true;
+        self.keeper.leave(self.cluster_id)
+    }
+}
+
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        if !self.left {
+            self.keeper.leave(self.cluster_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tikv_util::config::ReadableDuration;
+
+    #[test]
+    fn test_import_mode_keeper() {
+        let mut cfg = Config::default();
+        cfg.import_mode_renew_interval = ReadableDuration::millis(100);
+        let keeper = ImportModeKeeper::new(&cfg);
+
+        let job1 = keeper.enter(1);
+        let job2 = keeper.enter(1);
+        let job3 = keeper.enter(2);
+        // Just switched to import mode.
+        assert!(!keeper.should_renew(1));
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(keeper.should_renew(1));
+        assert!(!keeper.should_renew(1));
+        assert!(keeper.should_renew(2));
+
+        assert!(!job1.leave());
+        assert!(job2.leave());
+        assert!(job3.leave());
+        assert!(keeper.clusters.lock().unwrap().is_empty());
+
+        // Dropped jobs leave the cluster too.
+        let job1 = keeper.enter(1);
+        drop(keeper.enter(1));
+        assert!(job1.leave());
+        assert!(keeper.clusters.lock().unwrap().is_empty());
+
+        // Import mode requested explicitly is kept.
+        keeper.set_explicit_mode(1, SwitchMode::Import);
+        assert!(!keeper.enter(1).leave());
+        assert!(keeper.enter(2).leave());
+        keeper.set_explicit_mode(1, SwitchMode::Normal);
+        assert!(keeper.enter(1).leave());
+        assert!(keeper.clusters.lock().unwrap().is_empty());
+    }
+}
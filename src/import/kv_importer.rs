@@ -3,46 +3,98 @@
 use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
+use futures::channel::oneshot;
 use kvproto::import_kvpb::*;
 use uuid::Uuid;
 
-use collections::HashMap;
+use collections::{HashMap, HashSet};
+use pd_client::Config as PdConfig;
 use tikv::config::DbConfig;
 
+use super::checksum::EngineChecksum;
 use super::client::*;
+use super::duplicate::DuplicateKey;
 use super::engine::*;
 use super::import::*;
+use super::import_mode::ImportModeKeeper;
+use super::limiter::UploadLimiter;
+use super::manifest::*;
+use super::progress::*;
 use super::{Config, Error, Result};
+use encryption_export::DataKeyManager;
 use security::SecurityManager;
 
 pub struct Inner {
     engines: HashMap<Uuid, Arc<EngineFile>>,
     import_jobs: HashMap<Uuid, Arc<ImportJob<Client>>>,
+    // Status of finished import jobs, kept until the engine is imported
+    // again or cleaned up.
+    import_results: HashMap<Uuid, ImportStatus>,
+    // Requests waiting for aborted import jobs to stop.
+    abort_waiters: HashMap<Uuid, Vec<oneshot::Sender<()>>>,
+    // Clients of PD clusters, indexed by the sorted PD endpoints.
+    clients: HashMap<String, Client>,
+    // Closed engines being read outside of the lock, which can not be
+    // imported or cleaned up meanwhile.
+    reading: HashSet<Uuid>,
+}
+
+/// ReadLease marks a closed engine as being read, the engine is released
+/// when the lease is dropped.
+struct ReadLease<'a> {
+    inner: &'a Mutex<Inner>,
+    uuid: Uuid,
+}
+
+impl Drop for ReadLease<'_> {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().reading.remove(&self.uuid);
+    }
 }
 
 /// KVImporter manages all engines according to UUID.
 pub struct KVImporter {
     cfg: Config,
+    pd_cfg: PdConfig,
     dir: EngineDir,
     inner: Mutex<Inner>,
-    pub(super) security_mgr: Arc<SecurityManager>,
+    import_mode: ImportModeKeeper,
+    // Upload limits are shared by all import jobs, so that concurrent jobs
+    // can not exceed them together.
+    limiter: Arc<UploadLimiter>,
+    security_mgr: Arc<SecurityManager>,
 }
 
 impl KVImporter {
     pub fn new(
         cfg: Config,
         db_cfg: DbConfig,
+        pd_cfg: PdConfig,
         security_mgr: Arc<SecurityManager>,
+        key_manager: Option<Arc<DataKeyManager>>,
     ) -> Result<KVImporter> {
-        let dir = EngineDir::new(&cfg.import_dir, db_cfg, security_mgr.clone())?;
+        let dir = EngineDir::new(&cfg, db_cfg, key_manager)?;
+        let mut engines = HashMap::default();
+        for engine in dir.recover()? {
+            info!("recover engine completed"; "engine" => ?engine, "manifest" => ?engine.manifest());
+            engines.insert(engine.uuid, Arc::new(engine));
+        }
         Ok(KVImporter {
+            import_mode: ImportModeKeeper::new(&cfg),
+            limiter: Arc::new(UploadLimiter::new(&cfg)),
             cfg,
+            pd_cfg,
             dir,
             inner: Mutex::new(Inner {
-                engines: HashMap::default(),
+                engines,
                 import_jobs: HashMap::default(),
+                import_results: HashMap::default(),
+                abort_waiters: HashMap::default(),
+                clients: HashMap::default(),
+                reading: HashSet::default(),
             }),
             security_mgr,
         })
@@ -116,30 +168,133 @@ impl KVImporter {
         }
     }
 
-    /// Import the engine to TiKV stores.
-    /// Engine can not be imported before it is closed.
-    pub async fn import_engine(&self, uuid: Uuid, pd_addr: &str) -> Result<()> {
+    /// Returns the PD endpoints in `pd_addr`, which is a comma-separated
+    /// list of endpoints. The default endpoints are used if `pd_addr` is
+    /// empty.
+    fn pd_endpoints(&self, pd_addr: &str) -> Result<Vec<String>> {
+        let mut endpoints: Vec<String> = pd_addr
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(ToOwned::to_owned)
+            .collect();
+        if endpoints.is_empty() {
+            endpoints = self.pd_cfg.endpoints.clone();
+        }
+        if endpoints.is_empty() {
+            return Err(Error::InvalidPdAddr(pd_addr.to_owned()));
+        }
+        endpoints.sort();
+        endpoints.dedup();
+        Ok(endpoints)
+    }
+
+    /// Returns the client of the PD cluster at `pd_addr`. Clients are
+    /// cached, so that all requests to the same cluster share the PD
+    /// connection and the store channels.
+    pub async fn client(&self, pd_addr: &str) -> Result<Client> {
+        let endpoints = self.pd_endpoints(pd_addr)?;
+        let key = endpoints.join(",");
+        let client = self.inner.lock().unwrap().clients.get(&key).cloned();
+        if let Some(client) = client {
+            return Ok(client);
+        }
+
+        // PD leader changes are handled by the PD client.
+        let pd_cfg = PdConfig {
+            endpoints,
+            ..self.pd_cfg.clone()
+        };
         let client = Client::new(
-            pd_addr,
+            &pd_cfg,
             self.cfg.num_import_jobs,
-            self.cfg.min_available_ratio,
+            &self.cfg,
             self.security_mgr.clone(),
         )
         .await?;
+        let mut inner = self.inner.lock().unwrap();
+        // Another request may have created a client for the same cluster.
+        let client = inner.clients.entry(key).or_insert(client).clone();
+        Ok(client)
+    }
+
+    /// Switches the target cluster to the requested mode. Import mode
+    /// requested explicitly is kept after import jobs of the cluster finish.
+    pub async fn switch_mode(&self, req: &SwitchModeRequest) -> Result<()> {
+        let client = self.client(req.get_pd_addr()).await?;
+        let request = req.get_request();
+        client
+            .switch_cluster(request, self.cfg.strict_cluster_request)
+            .await?;
+        self.import_mode
+            .set_explicit_mode(client.cluster_id()?, request.get_mode());
+        Ok(())
+    }
+
+    /// Import the engine to TiKV stores.
+    /// Engine can not be imported before it is closed.
+    pub async fn import_engine(&self, uuid: Uuid, pd_addr: &str) -> Result<()> {
+        let job = self.new_import_job(uuid, pd_addr).await?;
+        self.run_import_job(uuid, job).await
+    }
+
+    /// Create an import job for the engine, the job must be run by
+    /// `run_import_job` later, which can be done in background.
+    /// Engine can not be imported before it is closed.
+    pub async fn new_import_job(
+        &self,
+        uuid: Uuid,
+        pd_addr: &str,
+    ) -> Result<Arc<ImportJob<Client>>> {
+        let client = self.client(pd_addr).await?;
+        // Finished ranges are only skipped when importing to the same cluster.
+        let cluster_id = client.cluster_id()?;
         let job = {
             let mut inner = self.inner.lock().unwrap();
             // One engine only related to one ImportJob
-            if inner.engines.contains_key(&uuid) || inner.import_jobs.contains_key(&uuid) {
+            if inner.engines.contains_key(&uuid)
+                || inner.import_jobs.contains_key(&uuid)
+                || inner.reading.contains(&uuid)
+            {
                 return Err(Error::EngineInUse(uuid));
             }
             let engine = self.dir.import(uuid)?;
-            let job = Arc::new(ImportJob::new(self.cfg.clone(), client, engine));
+            let checkpoint = self.dir.checkpoint(uuid, cluster_id)?;
+            self.dir.set_state(uuid, EngineState::Importing)?;
+            let job = Arc::new(ImportJob::new(
+                self.cfg.clone(),
+                client,
+                engine,
+                checkpoint,
+                Arc::clone(&self.limiter),
+            ));
             inner.import_jobs.insert(uuid, Arc::clone(&job));
+            inner.import_results.remove(&uuid);
             job
         };
+        Ok(job)
+    }
 
-        let res = job.run().await;
-        self.inner.lock().unwrap().import_jobs.remove(&uuid);
+    /// Run the import job created by `new_import_job`. The target cluster is
+    /// kept in import mode while the job is running.
+    pub async fn run_import_job(&self, uuid: Uuid, job: Arc<ImportJob<Client>>) -> Result<()> {
+        let res = self.import_mode.run(job.client(), job.run()).await;
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.import_jobs.remove(&uuid);
+            inner.import_results.insert(uuid, job.progress().status());
+            let state = if res.is_ok() {
+                EngineState::Imported
+            } else {
+                EngineState::Closed
+            };
+            if let Err(e) = self.dir.set_state(uuid, state) {
+                warn!("update engine state failed"; "uuid" => %uuid, "state" => ?state, "err" => %e);
+            }
+            for tx in inner.abort_waiters.remove(&uuid).unwrap_or_default() {
+                let _ = tx.send(());
+            }
+        }
 
         match res {
             Ok(_) => {
@@ -153,13 +308,172 @@ impl KVImporter {
         }
     }
 
+    /// Abort the running import job of the engine, and wait for the job to
+    /// stop. The engine is left closed and can be imported or cleaned up again.
+    pub async fn abort_import_engine(&self, uuid: Uuid) -> Result<()> {
+        let stopped = {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.import_jobs.get(&uuid) {
+                Some(job) => job.abort(),
+                None => return Err(Error::EngineNotFound(uuid)),
+            }
+            let (tx, rx) = oneshot::channel();
+            inner.abort_waiters.entry(uuid).or_default().push(tx);
+            rx
+        };
+        // Notified by `run_import_job` once the job is removed.
+        let _ = stopped.await;
+        info!("abort import completed"; "uuid" => %uuid);
+        Ok(())
+    }
+
+    /// Returns the status of the running or finished import job of the engine.
+    pub fn import_status(&self, uuid: Uuid) -> Result<ImportStatus> {
+        let inner = self.inner.lock().unwrap();
+        if let Some(job) = inner.import_jobs.get(&uuid) {
+            return Ok(job.progress().status());
+        }
+        match inner.import_results.get(&uuid) {
+            Some(status) => Ok(status.clone()),
+            None => Err(Error::EngineNotFound(uuid)),
+        }
+    }
+
+    /// Leases a closed engine to be read outside of the lock. Only one lease
+    /// of an engine can be held at a time, since RocksDB can not be opened
+    /// more than once.
+    fn lease_closed_engine(&self, uuid: Uuid) -> Result<ReadLease<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.engines.contains_key(&uuid)
+            || inner.import_jobs.contains_key(&uuid)
+            || inner.reading.contains(&uuid)
+        {
+            return Err(Error::EngineInUse(uuid));
+        }
+        if !self.dir.join(uuid).save.exists() {
+            return Err(Error::EngineNotFound(uuid));
+        }
+        inner.reading.insert(uuid);
+        Ok(ReadLease {
+            inner: &self.inner,
+            uuid,
+        })
+    }
+
+    /// Returns the duplicate keys detected while writing the engine, see
+    /// `import.detect-duplicate-keys`. The engine can be open or closed, but
+    /// not importing.
+    pub fn duplicate_keys(&self, uuid: Uuid) -> Result<Vec<DuplicateKey>> {
+        let engine = self.inner.lock().unwrap().engines.get(&uuid).cloned();
+        if let Some(engine) = engine {
+            return engine.duplicate_keys();
+        }
+        // The closed engine is opened outside of the lock.
+        let _lease = self.lease_closed_engine(uuid)?;
+        self.dir.import(uuid)?.duplicate_keys()
+    }
+
+    /// Returns the statistics of an open, closed or importing engine, so that
+    /// the engine can be checked before it is imported.
+    pub fn engine_stats(&self, uuid: Uuid) -> Result<EngineStats> {
+        enum EngineRef {
+            Open(Arc<EngineFile>),
+            Importing(Arc<ImportJob<Client>>),
+            Closed,
+        }
+
+        let engine = {
+            let inner = self.inner.lock().unwrap();
+            if let Some(engine) = inner.engines.get(&uuid) {
+                EngineRef::Open(Arc::clone(engine))
+            } else if let Some(job) = inner.import_jobs.get(&uuid) {
+                EngineRef::Importing(Arc::clone(job))
+            } else {
+                EngineRef::Closed
+            }
+        };
+        // Engines are scanned outside of the lock.
+        let res = match engine {
+            EngineRef::Open(engine) => engine.stats(),
+            EngineRef::Importing(job) => job.engine().stats(),
+            // The closed engine can not be imported or cleaned up until the
+            // lease is dropped.
+            EngineRef::Closed => self
+                .lease_closed_engine(uuid)
+                .and_then(|_lease| self.dir.import(uuid)?.stats()),
+        };
+        match res {
+            Ok(stats) => {
+                info!("engine stats"; "uuid" => %uuid, "stats" => ?stats);
+                Ok(stats)
+            }
+            Err(e) => {
+                error!("get engine stats failed"; "uuid" => %uuid, "err" => %e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Returns the checksum of a closed engine, which can be compared with
+    /// `ADMIN CHECKSUM TABLE` of TiDB after the engine is imported. Checksums
+    /// of each table are returned if `per_table` is true.
+    ///
+    /// A closed engine is never written again, so the checksum is computed
+    /// only once and cached in `$root/$uuid.checksum`.
+    pub fn checksum_engine(&self, uuid: Uuid, per_table: bool) -> Result<EngineChecksum> {
+        let lease = self.lease_closed_engine(uuid)?;
+        let mut checksum = match self.dir.load_checksum(uuid)? {
+            Some(checksum) => checksum,
+            None => {
+                // The engine is scanned outside of the lock.
+                let checksum = self.dir.import(uuid)?.checksum()?;
+                // Saves the checksum under the lock, only if the engine is
+                // still leased, so that it is never saved for an engine which
+                // is cleaned up or imported.
+                let inner = self.inner.lock().unwrap();
+                if !inner.reading.contains(&uuid) {
+                    return Err(Error::EngineInUse(uuid));
+                }
+                self.dir.save_checksum(uuid, &checksum)?;
+                checksum
+            }
+        };
+        drop(lease);
+        if !per_table {
+            checksum.tables.clear();
+        }
+        info!("checksum engine completed"; "uuid" => %uuid, "checksum" => ?checksum.total);
+        Ok(checksum)
+    }
+
+    /// Reset the import checkpoint of the engine, so that the next import
+    /// will upload all data of the engine again.
+    /// Checkpoint can not be reset when the engine is importing.
+    pub fn reset_import_checkpoint(&self, uuid: Uuid) -> Result<()> {
+        let inner = self.inner.lock().unwrap();
+        if inner.import_jobs.contains_key(&uuid) {
+            return Err(Error::EngineInUse(uuid));
+        }
+        match self.dir.reset_checkpoint(uuid) {
+            Ok(_) => {
+                info!("reset import checkpoint completed"; "uuid" => %uuid);
+                Ok(())
+            }
+            Err(e) => {
+                error!("reset import checkpoint failed"; "uuid" => %uuid, "err" => %e);
+                Err(e)
+            }
+        }
+    }
+
     /// Clean up the engine.
-    /// Engine can not be cleaned up when it is writing or importing.
+    /// Engine can not be cleaned up when it is writing, importing or being
+    /// read.
     pub fn cleanup_engine(&self, uuid: Uuid) -> Result<()> {
         // Drop the engine outside of the lock.
-        let _ = {
+        let engine = {
             let mut inner = self.inner.lock().unwrap();
-            if inner.import_jobs.contains_key(&uuid) {
+            if inner.import_jobs.contains_key(&uuid) || inner.reading.contains(&uuid) {
                 return Err(Error::EngineInUse(uuid));
             }
             if let Some(engine) = inner.engines.remove(&uuid) {
@@ -174,6 +488,11 @@ impl KVImporter {
                 None
             }
         };
+        if let Some(mut engine) = engine {
+            // No need to sync an engine which is going to be removed.
+            engine.discard();
+        }
+        self.inner.lock().unwrap().import_results.remove(&uuid);
 
         match self.dir.cleanup(uuid) {
             Ok(_) => {
@@ -191,33 +510,56 @@ impl KVImporter {
 /// EngineDir is responsible for managing engine directories.
 ///
 /// The temporary RocksDB engine is placed in `$root/.temp/$uuid`. After writing
-/// is completed, the files are stored in `$root/$uuid`.
+/// is completed, the files are stored in `$root/$uuid`. The state of each
+/// engine is recorded in `$root/$uuid.manifest`, ranges which have been
+/// imported are recorded in `$root/$uuid.checkpoint`, and the checksum of a
+/// closed engine is cached in `$root/$uuid.checksum`. SSTs generated for
+/// importing are written to `$root/.sst` once the SST memory budget is used up.
+///
+/// If encryption is enabled, engine files are encrypted with data keys
+/// recorded in the key dictionary of `$root`.
 pub struct EngineDir {
     db_cfg: DbConfig,
-    security_mgr: Arc<SecurityManager>,
+    key_manager: Option<Arc<DataKeyManager>>,
+    // Shared by all engines being imported.
+    sst_memory_budget: Arc<SSTMemoryBudget>,
+    detect_duplicate: bool,
     root_dir: PathBuf,
     temp_dir: PathBuf,
+    sst_dir: PathBuf,
 }
 
 impl EngineDir {
     const TEMP_DIR: &'static str = ".temp";
+    const SST_DIR: &'static str = ".sst";
+    const MANIFEST_EXTENSION: &'static str = "manifest";
+    const CHECKPOINT_EXTENSION: &'static str = "checkpoint";
+    const CHECKSUM_EXTENSION: &'static str = "checksum";
 
-    fn new<P: AsRef<Path>>(
-        root: P,
+    fn new(
+        cfg: &Config,
         db_cfg: DbConfig,
-        security_mgr: Arc<SecurityManager>,
+        key_manager: Option<Arc<DataKeyManager>>,
     ) -> Result<EngineDir> {
-        let root_dir = root.as_ref().to_owned();
+        let root_dir = PathBuf::from(&cfg.import_dir);
         let temp_dir = root_dir.join(Self::TEMP_DIR);
-        if temp_dir.exists() {
-            fs::remove_dir_all(&temp_dir)?;
-        }
         fs::create_dir_all(&temp_dir)?;
+        // SSTs left by the last run are useless.
+        let sst_dir = root_dir.join(Self::SST_DIR);
+        if sst_dir.exists() {
+            remove_engine_dir(&sst_dir, key_manager.as_deref())?;
+        }
+        fs::create_dir_all(&sst_dir)?;
+        let sst_memory_budget =
+            SSTMemoryBudget::new(cfg.sst_memory_budget.0, cfg.region_split_size.0);
         Ok(EngineDir {
             db_cfg,
-            security_mgr,
+            key_manager,
+            sst_memory_budget: Arc::new(sst_memory_budget),
+            detect_duplicate: cfg.detect_duplicate_keys,
             root_dir,
             temp_dir,
+            sst_dir,
         })
     }
 
@@ -225,51 +567,252 @@ impl EngineDir {
         let file_name = format!("{}", uuid);
         let save_path = self.root_dir.join(&file_name);
         let temp_path = self.temp_dir.join(&file_name);
+        let manifest_path = save_path.with_extension(Self::MANIFEST_EXTENSION);
+        let checkpoint_path = save_path.with_extension(Self::CHECKPOINT_EXTENSION);
+        let checksum_path = save_path.with_extension(Self::CHECKSUM_EXTENSION);
         EnginePath {
             save: save_path,
             temp: temp_path,
+            manifest: manifest_path,
+            checkpoint: checkpoint_path,
+            checksum: checksum_path,
         }
     }
 
+    /// Recovers engines according to the manifests in `$root`.
+    ///
+    /// Engines which were open are reopened for writing. Engines which were
+    /// importing are marked as closed since their import jobs have gone.
+    /// Temp directories which can not be recovered are removed, and so are
+    /// temp files left by `save_json`. Corrupted manifests are skipped.
+    fn recover(&self) -> Result<Vec<EngineFile>> {
+        let mut engines = Vec::new();
+        for entry in fs::read_dir(&self.root_dir)? {
+            let manifest_path = entry?.path();
+            if manifest_path.is_file()
+                && manifest_path.extension().map_or(false, |ext| ext == "tmp")
+            {
+                warn!("remove unfinished temp file"; "path" => ?manifest_path);
+                fs::remove_file(&manifest_path)?;
+                continue;
+            }
+            if manifest_path
+                .extension()
+                .map_or(true, |ext| ext != Self::MANIFEST_EXTENSION)
+            {
+                continue;
+            }
+            let uuid = match manifest_path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| Uuid::parse_str(s).ok())
+            {
+                Some(uuid) => uuid,
+                None => {
+                    warn!("skip unknown manifest"; "path" => ?manifest_path);
+                    continue;
+                }
+            };
+
+            let path = self.join(uuid);
+            let mut manifest: EngineManifest = match load_json(&path.manifest) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    warn!("skip corrupted manifest"; "path" => ?path.manifest, "err" => %e);
+                    continue;
+                }
+            };
+            if manifest.format_version != ENGINE_FORMAT_VERSION {
+                // KV pairs in other formats can not be read, so the engine
+                // must be written again.
+                warn!("remove engine in unsupported format"; "path" => ?path, "format_version" => manifest.format_version);
+                self.cleanup(uuid)?;
+                continue;
+            }
+            match manifest.state {
+                EngineState::Open if path.temp.exists() => {
+                    let engine = EngineFile::new(
+                        uuid,
+                        path,
+                        manifest,
+                        self.db_cfg.clone(),
+                        self.key_manager.clone(),
+                        self.detect_duplicate,
+                    )?;
+                    engines.push(engine);
+                }
+                // The engine has been moved to the save directory, but the
+                // process crashed before the manifest was updated by `close`.
+                EngineState::Open if path.save.exists() => {
+                    manifest.state = EngineState::Closed;
+                    save_json(&path.manifest, &manifest)?;
+                }
+                EngineState::Open => {
+                    warn!("engine files not found, remove manifest"; "path" => ?path);
+                    fs::remove_file(&path.manifest)?;
+                }
+                EngineState::Importing => {
+                    manifest.state = EngineState::Closed;
+                    save_json(&path.manifest, &manifest)?;
+                }
+                EngineState::Closed | EngineState::Imported => {}
+            }
+        }
+
+        for entry in fs::read_dir(&self.temp_dir)? {
+            let temp_path = entry?.path();
+            if !engines.iter().any(|e| e.path.temp == temp_path) {
+                warn!("remove unrecoverable engine files"; "path" => ?temp_path);
+                remove_engine_dir(&temp_path, self.key_manager.as_deref())?;
+            }
+        }
+        Ok(engines)
+    }
+
     /// Creates an engine from `$root/.temp/$uuid` for storing and sorting KV pairs temporarily.
     fn open(&self, uuid: Uuid) -> Result<EngineFile> {
         let path = self.join(uuid);
         if path.save.exists() {
             return Err(Error::FileExists(path.save));
         }
-        EngineFile::new(uuid, path, self.db_cfg.clone(), self.security_mgr.clone())
+        EngineFile::new(
+            uuid,
+            path,
+            EngineManifest::default(),
+            self.db_cfg.clone(),
+            self.key_manager.clone(),
+            self.detect_duplicate,
+        )
+    }
+
+    /// Updates the engine state recorded in `$root/$uuid.manifest`.
+    fn set_state(&self, uuid: Uuid, state: EngineState) -> Result<()> {
+        let path = self.join(uuid);
+        let mut manifest = if path.manifest.exists() {
+            load_json(&path.manifest)?
+        } else {
+            EngineManifest::default()
+        };
+        manifest.state = state;
+        save_json(&path.manifest, &manifest)
+    }
+
+    /// Loads the import checkpoint of importing to `cluster_id` from
+    /// `$root/$uuid.checkpoint`.
+    fn checkpoint(&self, uuid: Uuid, cluster_id: u64) -> Result<ImportCheckpoint> {
+        ImportCheckpoint::load(self.join(uuid).checkpoint, cluster_id)
+    }
+
+    /// Removes the import checkpoint of the engine.
+    fn reset_checkpoint(&self, uuid: Uuid) -> Result<()> {
+        let path = self.join(uuid).checkpoint;
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
     }
 
     /// Creates an engine from `$root/$uuid` for importing data.
     fn import(&self, uuid: Uuid) -> Result<Engine> {
         let path = self.join(uuid);
-        Engine::new(
+        let mut engine = Engine::new(
             &path.save,
             uuid,
             self.db_cfg.clone(),
-            self.security_mgr.clone(),
-        )
+            self.key_manager.clone(),
+            false,
+        )?;
+        engine.set_sst_memory_budget(Arc::clone(&self.sst_memory_budget));
+        engine.set_sst_dir(self.sst_dir.clone());
+        Ok(engine)
+    }
+
+    /// Loads the cached checksum of a closed engine from
+    /// `$root/$uuid.checksum`, returns None if it is not cached yet.
+    fn load_checksum(&self, uuid: Uuid) -> Result<Option<EngineChecksum>> {
+        let path = self.join(uuid);
+        if !path.checksum.exists() {
+            return Ok(None);
+        }
+        load_json(&path.checksum).map(Some)
     }
 
-    /// Cleans up directories for both `$root/.temp/$uuid` and `$root/$uuid`
+    /// Saves the checksum of a closed engine to `$root/$uuid.checksum`.
+    fn save_checksum(&self, uuid: Uuid, checksum: &EngineChecksum) -> Result<()> {
+        save_json(&self.join(uuid).checksum, checksum)
+    }
+
+    /// Cleans up directories for both `$root/.temp/$uuid` and `$root/$uuid`,
+    /// and files `$root/$uuid.manifest`, `$root/$uuid.checkpoint` and
+    /// `$root/$uuid.checksum`.
     fn cleanup(&self, uuid: Uuid) -> Result<EnginePath> {
         let path = self.join(uuid);
         if path.save.exists() {
-            fs::remove_dir_all(&path.save)?;
+            remove_engine_dir(&path.save, self.key_manager.as_deref())?;
         }
         if path.temp.exists() {
-            fs::remove_dir_all(&path.temp)?;
+            remove_engine_dir(&path.temp, self.key_manager.as_deref())?;
+        }
+        if path.manifest.exists() {
+            fs::remove_file(&path.manifest)?;
+        }
+        if path.checkpoint.exists() {
+            fs::remove_file(&path.checkpoint)?;
+        }
+        if path.checksum.exists() {
+            fs::remove_file(&path.checksum)?;
         }
         Ok(path)
     }
 }
 
+/// Moves the engine files from `src` to `dst`, the data keys of encrypted
+/// files are moved along with them.
+fn rename_engine_dir(src: &Path, dst: &Path, key_manager: Option<&DataKeyManager>) -> Result<()> {
+    let key_manager = match key_manager {
+        Some(m) => m,
+        None => return Ok(fs::rename(src, dst)?),
+    };
+    // RocksDB doesn't create sub directories, so files are all at the top
+    // level of the engine directory.
+    let mut file_names = Vec::new();
+    for entry in fs::read_dir(src)? {
+        file_names.push(entry?.file_name());
+    }
+    for name in &file_names {
+        let (src_file, dst_file) = (src.join(name), dst.join(name));
+        key_manager.link_file(src_file.to_str().unwrap(), dst_file.to_str().unwrap())?;
+    }
+    fs::rename(src, dst)?;
+    for name in &file_names {
+        key_manager.delete_file(src.join(name).to_str().unwrap())?;
+    }
+    Ok(())
+}
+
+/// Removes the engine files in `path` and their data keys.
+fn remove_engine_dir(path: &Path, key_manager: Option<&DataKeyManager>) -> Result<()> {
+    if let Some(key_manager) = key_manager {
+        for entry in fs::read_dir(path)? {
+            key_manager.delete_file(entry?.path().to_str().unwrap())?;
+        }
+    }
+    fs::remove_dir_all(path)?;
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct EnginePath {
     // The path of the engine that has been closed.
     save: PathBuf,
     // The path of the engine that is being wrote.
     temp: PathBuf,
+    // The path of the engine manifest.
+    manifest: PathBuf,
+    // The path of the import checkpoint.
+    checkpoint: PathBuf,
+    // The path of the cached engine checksum.
+    checksum: PathBuf,
 }
 
 impl fmt::Debug for EnginePath {
@@ -277,16 +820,28 @@ impl fmt::Debug for EnginePath {
         f.debug_struct("EnginePath")
             .field("save", &self.save)
             .field("temp", &self.temp)
+            .field("manifest", &self.manifest)
+            .field("checkpoint", &self.checkpoint)
+            .field("checksum", &self.checksum)
             .finish()
     }
 }
 
+/// Sync the write progress of an engine after this size of data is written.
+const SYNC_WRITE_BYTES: usize = 1024 * 1024 * 1024;
+
 /// EngineFile creates an engine in the temp directory for writing, and when
 /// writing is completed, it moves files to the save directory.
+///
+/// Files are kept when an EngineFile is dropped, so that the engine can be
+/// recovered after the importer restarts.
 pub struct EngineFile {
     uuid: Uuid,
     path: EnginePath,
     engine: Option<Engine>,
+    key_manager: Option<Arc<DataKeyManager>>,
+    manifest: Mutex<EngineManifest>,
+    unsynced_bytes: AtomicUsize,
 }
 
 impl EngineFile {
@@ -294,25 +849,97 @@ impl EngineFile {
     fn new(
         uuid: Uuid,
         path: EnginePath,
+        manifest: EngineManifest,
         db_cfg: DbConfig,
-        security_mgr: Arc<SecurityManager>,
+        key_manager: Option<Arc<DataKeyManager>>,
+        detect_duplicate: bool,
     ) -> Result<EngineFile> {
-        let engine = Engine::new(&path.temp, uuid, db_cfg, security_mgr)?;
+        let engine = Engine::new(
+            &path.temp,
+            uuid,
+            db_cfg,
+            key_manager.clone(),
+            detect_duplicate,
+        )?;
+        save_json(&path.manifest, &manifest)?;
         Ok(EngineFile {
             uuid,
             path,
             engine: Some(engine),
+            key_manager,
+            manifest: Mutex::new(manifest),
+            unsynced_bytes: AtomicUsize::new(0),
         })
     }
 
+    /// Returns the manifest of the engine. The write progress in the
+    /// returned manifest may not have been persisted yet.
+    pub fn manifest(&self) -> EngineManifest {
+        self.manifest.lock().unwrap().clone()
+    }
+
     /// Writes KV pairs to the engine, stream version.
-    pub fn write(&self, batch: WriteBatch) -> Result<usize> {
-        self.engine.as_ref().unwrap().write(batch)
+    pub fn write(&self, batch: WriteBatch, source: &str) -> Result<usize> {
+        let count = batch.get_mutations().len();
+        let size = self.engine.as_ref().unwrap().write(batch, source)?;
+        self.record_write(count, size)?;
+        Ok(size)
     }
 
     /// Writes KV pairs to the engine, single message version.
-    pub fn write_v3(&self, commit_ts: u64, pairs: &[KvPair]) -> Result<usize> {
-        self.engine.as_ref().unwrap().write_v3(commit_ts, pairs)
+    pub fn write_v3(&self, commit_ts: u64, pairs: &[KvPair], source: &str) -> Result<usize> {
+        let size = self
+            .engine
+            .as_ref()
+            .unwrap()
+            .write_v3(commit_ts, pairs, source)?;
+        self.record_write(pairs.len(), size)?;
+        Ok(size)
+    }
+
+    /// Writes puts and deletions of raw keys to the engine.
+    pub fn write_mutations(
+        &self,
+        commit_ts: u64,
+        mutations: &[(&[u8], EngineValue<'_>)],
+        source: &str,
+    ) -> Result<usize> {
+        let size = self
+            .engine
+            .as_ref()
+            .unwrap()
+            .write_mutations(commit_ts, mutations, source)?;
+        self.record_write(mutations.len(), size)?;
+        Ok(size)
+    }
+
+    pub fn duplicate_keys(&self) -> Result<Vec<DuplicateKey>> {
+        self.engine.as_ref().unwrap().duplicate_keys()
+    }
+
+    pub fn stats(&self) -> Result<EngineStats> {
+        self.engine.as_ref().unwrap().stats()
+    }
+
+    /// Records the write progress, and syncs it to the manifest after
+    /// `SYNC_WRITE_BYTES` of data have been written since the last sync.
+    fn record_write(&self, count: usize, size: usize) -> Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.kv_count += count as u64;
+        manifest.write_bytes += size as u64;
+        let unsynced_bytes = self.unsynced_bytes.fetch_add(size, Ordering::SeqCst) + size;
+        if unsynced_bytes >= SYNC_WRITE_BYTES {
+            self.sync(&manifest)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the engine and saves the manifest. Data is written without
+    /// WAL, so the write progress is only durable after a flush.
+    fn sync(&self, manifest: &EngineManifest) -> Result<()> {
+        self.engine.as_ref().unwrap().flush(true)?;
+        self.unsynced_bytes.store(0, Ordering::SeqCst);
+        save_json(&self.path.manifest, manifest)
     }
 
     /// Finish writing and move files from temp directory to save directory.
@@ -321,24 +948,31 @@ impl EngineFile {
         if self.path.save.exists() {
             return Err(Error::FileExists(self.path.save.clone()));
         }
-        fs::rename(&self.path.temp, &self.path.save)?;
-        Ok(())
+        rename_engine_dir(
+            &self.path.temp,
+            &self.path.save,
+            self.key_manager.as_deref(),
+        )?;
+        let manifest = self.manifest.get_mut().unwrap();
+        manifest.state = EngineState::Closed;
+        save_json(&self.path.manifest, &*manifest)
     }
 
-    /// Close the engine and remove all temp files.
-    fn cleanup(&mut self) -> Result<()> {
+    /// Close the engine without syncing, files are left to be removed by
+    /// the caller.
+    fn discard(&mut self) {
         self.engine.take();
-        if self.path.temp.exists() {
-            fs::remove_dir_all(&self.path.temp)?;
-        }
-        Ok(())
     }
 }
 
 impl Drop for EngineFile {
     fn drop(&mut self) {
-        if let Err(e) = self.cleanup() {
-            warn!("cleanup"; "engine file" => ?self, "err" => %e);
+        if self.engine.is_none() {
+            return;
+        }
+        let manifest = self.manifest.lock().unwrap();
+        if let Err(e) = self.sync(&manifest) {
+            warn!("sync"; "engine file" => ?self, "err" => %e);
         }
     }
 }
@@ -355,8 +989,12 @@ impl fmt::Debug for EngineFile {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::import::common::new_range;
+    use crate::import::test_helpers::new_key_manager;
 
+    use futures::executor::block_on;
     use tempdir::TempDir;
+    use txn_types::{Key, TimeStamp};
 
     #[test]
     fn test_kv_importer() {
@@ -364,7 +1002,14 @@ mod tests {
 
         let mut cfg = Config::default();
         cfg.import_dir = temp_dir.path().to_str().unwrap().to_owned();
-        let importer = KVImporter::new(cfg, DbConfig::default(), Arc::default()).unwrap();
+        let importer = KVImporter::new(
+            cfg,
+            DbConfig::default(),
+            PdConfig::default(),
+            Arc::default(),
+            None,
+        )
+        .unwrap();
 
         let uuid = Uuid::new_v4();
         // Can not bind to an unopened engine.
@@ -372,52 +1017,389 @@ mod tests {
         importer.open_engine(uuid).unwrap();
         let engine = importer.bind_engine(uuid).unwrap();
 
-        engine.write(WriteBatch::default()).unwrap();
-        engine.write_v3(0, &[KvPair::default()]).unwrap();
+        engine.write(WriteBatch::default(), "test").unwrap();
+        engine.write_v3(0, &[KvPair::default()], "test").unwrap();
 
         // Can not close an in use engine.
         assert!(importer.close_engine(uuid).is_err());
         drop(engine);
         importer.close_engine(uuid).unwrap();
+
+        // Can not abort or query an engine which is not importing.
+        assert!(block_on(importer.abort_import_engine(uuid)).is_err());
+        assert!(importer.import_status(uuid).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let temp_dir = TempDir::new("test_duplicate_keys").unwrap();
+
+        let mut cfg = Config::default();
+        cfg.import_dir = temp_dir.path().to_str().unwrap().to_owned();
+        cfg.detect_duplicate_keys = true;
+        let importer = KVImporter::new(
+            cfg,
+            DbConfig::default(),
+            PdConfig::default(),
+            Arc::default(),
+            None,
+        )
+        .unwrap();
+
+        let uuid = Uuid::new_v4();
+        assert!(importer.duplicate_keys(uuid).is_err());
+        importer.open_engine(uuid).unwrap();
+        let engine = importer.bind_engine(uuid).unwrap();
+        let mut pair = KvPair::default();
+        pair.set_key(b"k".to_vec());
+        pair.set_value(b"v1".to_vec());
+        engine.write_v3(1, &[pair.clone()], "s1").unwrap();
+        pair.set_value(b"v2".to_vec());
+        engine.write_v3(1, &[pair], "s2").unwrap();
+        drop(engine);
+
+        // Duplicate keys can be fetched before and after the engine is closed.
+        assert_eq!(importer.duplicate_keys(uuid).unwrap().len(), 1);
+        importer.close_engine(uuid).unwrap();
+        // A closed engine being read can not be read again or cleaned up.
+        let lease = importer.lease_closed_engine(uuid).unwrap();
+        assert!(importer.duplicate_keys(uuid).is_err());
+        assert!(importer.cleanup_engine(uuid).is_err());
+        drop(lease);
+        let dups = importer.duplicate_keys(uuid).unwrap();
+        assert_eq!(dups.len(), 1);
+        assert_eq!(dups[0].key, b"k");
+        assert_eq!(
+            (dups[0].old_value.as_deref(), dups[0].old_source.as_str()),
+            (Some(&b"v1"[..]), "s1")
+        );
+        assert_eq!(
+            (dups[0].new_value.as_deref(), dups[0].new_source.as_str()),
+            (Some(&b"v2"[..]), "s2")
+        );
+    }
+
+    #[test]
+    fn test_engine_stats() {
+        let temp_dir = TempDir::new("test_engine_stats").unwrap();
+
+        let mut cfg = Config::default();
+        cfg.import_dir = temp_dir.path().to_str().unwrap().to_owned();
+        let importer = KVImporter::new(
+            cfg,
+            DbConfig::default(),
+            PdConfig::default(),
+            Arc::default(),
+            None,
+        )
+        .unwrap();
+
+        let uuid = Uuid::new_v4();
+        assert!(importer.engine_stats(uuid).is_err());
+        importer.open_engine(uuid).unwrap();
+        let engine = importer.bind_engine(uuid).unwrap();
+        let pairs: Vec<_> = (0..3u8)
+            .map(|i| {
+                let mut pair = KvPair::default();
+                pair.set_key(vec![i]);
+                pair.set_value(vec![i]);
+                pair
+            })
+            .collect();
+        engine.write_v3(1, &pairs, "test").unwrap();
+        drop(engine);
+
+        let stats = importer.engine_stats(uuid).unwrap();
+        assert_eq!(stats.kvs, 3);
+        importer.close_engine(uuid).unwrap();
+        let lease = importer.lease_closed_engine(uuid).unwrap();
+        assert!(importer.engine_stats(uuid).is_err());
+        drop(lease);
+        let stats = importer.engine_stats(uuid).unwrap();
+        assert_eq!(stats.kvs, 3);
+        assert_eq!(stats.approximate_kvs, 3);
+        assert_eq!(stats.sst_files, 1);
+        assert_eq!((stats.smallest_key, stats.largest_key), (vec![0], vec![2]));
+        assert_eq!(stats.commit_ts, vec![1]);
+    }
+
+    #[test]
+    fn test_checksum_engine() {
+        let temp_dir = TempDir::new("test_checksum_engine").unwrap();
+
+        let mut cfg = Config::default();
+        cfg.import_dir = temp_dir.path().to_str().unwrap().to_owned();
+        let importer = KVImporter::new(
+            cfg,
+            DbConfig::default(),
+            PdConfig::default(),
+            Arc::default(),
+            None,
+        )
+        .unwrap();
+
+        let uuid = Uuid::new_v4();
+        assert!(importer.checksum_engine(uuid, false).is_err());
+        importer.open_engine(uuid).unwrap();
+        // Can not checksum an open engine.
+        assert!(importer.checksum_engine(uuid, false).is_err());
+        let mut pair = KvPair::default();
+        pair.set_key(b"t12345678_r1".to_vec());
+        pair.set_value(b"v".to_vec());
+        let engine = importer.bind_engine(uuid).unwrap();
+        engine.write_v3(1, &[pair], "test").unwrap();
+        drop(engine);
+        importer.close_engine(uuid).unwrap();
+
+        let checksum = importer.checksum_engine(uuid, true).unwrap();
+        assert_eq!(checksum.total.total_kvs, 1);
+        assert_eq!(checksum.tables.len(), 1);
+        assert!(importer.dir.join(uuid).checksum.exists());
+        // The cached checksum is returned.
+        let cached = importer.checksum_engine(uuid, false).unwrap();
+        assert_eq!(cached.total, checksum.total);
+        assert!(cached.tables.is_empty());
+        // Can not checksum an engine which is being read.
+        let lease = importer.lease_closed_engine(uuid).unwrap();
+        assert!(importer.checksum_engine(uuid, false).is_err());
+        drop(lease);
+
+        importer.cleanup_engine(uuid).unwrap();
+        assert!(!importer.dir.join(uuid).checksum.exists());
+    }
+
+    #[test]
+    fn test_pd_endpoints() {
+        let temp_dir = TempDir::new("test_pd_endpoints").unwrap();
+
+        let mut cfg = Config::default();
+        cfg.import_dir = temp_dir.path().to_str().unwrap().to_owned();
+        let importer = KVImporter::new(
+            cfg.clone(),
+            DbConfig::default(),
+            PdConfig::default(),
+            Arc::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(importer.pd_endpoints("pd1").unwrap(), vec!["pd1"]);
+        assert_eq!(
+            importer.pd_endpoints(" pd2, pd1,,pd2 ").unwrap(),
+            vec!["pd1", "pd2"]
+        );
+        // No default endpoints.
+        assert!(importer.pd_endpoints("").is_err());
+        assert!(importer.pd_endpoints(" , ").is_err());
+        drop(importer);
+
+        let pd_cfg = PdConfig::new(vec!["pd3".to_owned()]);
+        let importer =
+            KVImporter::new(cfg, DbConfig::default(), pd_cfg, Arc::default(), None).unwrap();
+        assert_eq!(importer.pd_endpoints("").unwrap(), vec!["pd3"]);
+        assert_eq!(importer.pd_endpoints("pd1").unwrap(), vec!["pd1"]);
+    }
+
+    #[test]
+    fn test_kv_importer_recover() {
+        let temp_dir = TempDir::new("test_kv_importer_recover").unwrap();
+
+        let mut cfg = Config::default();
+        cfg.import_dir = temp_dir.path().to_str().unwrap().to_owned();
+
+        let (uuid1, uuid2, uuid3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        {
+            let importer = KVImporter::new(
+                cfg.clone(),
+                DbConfig::default(),
+                PdConfig::default(),
+                Arc::default(),
+                None,
+            )
+            .unwrap();
+            importer.open_engine(uuid1).unwrap();
+            importer.open_engine(uuid2).unwrap();
+            importer.open_engine(uuid3).unwrap();
+
+            let engine = importer.bind_engine(uuid1).unwrap();
+            let mut pairs = vec![KvPair::default(); 2];
+            pairs[0].set_key(vec![1]);
+            pairs[1].set_key(vec![2]);
+            engine.write_v3(1, &pairs, "test").unwrap();
+            drop(engine);
+
+            importer.close_engine(uuid2).unwrap();
+            importer.cleanup_engine(uuid3).unwrap();
+        }
+
+        // Leave some files which can not be recovered.
+        let orphan = temp_dir.path().join(".temp").join("orphan");
+        fs::create_dir_all(&orphan).unwrap();
+        let orphan_sst = temp_dir.path().join(".sst").join("orphan.sst");
+        fs::write(&orphan_sst, b"sst").unwrap();
+        let orphan_tmp = temp_dir.path().join(format!("{}.manifest.tmp", uuid1));
+        fs::write(&orphan_tmp, b"{").unwrap();
+        let corrupted = temp_dir.path().join(format!("{}.manifest", Uuid::new_v4()));
+        fs::write(&corrupted, b"{").unwrap();
+        // Crashes before the manifest of the closed engine is updated.
+        let manifest_path = temp_dir.path().join(format!("{}.manifest", uuid2));
+        let mut manifest: EngineManifest = load_json(&manifest_path).unwrap();
+        manifest.state = EngineState::Open;
+        save_json(&manifest_path, &manifest).unwrap();
+
+        let importer = KVImporter::new(
+            cfg.clone(),
+            DbConfig::default(),
+            PdConfig::default(),
+            Arc::default(),
+            None,
+        )
+        .unwrap();
+        assert!(!orphan.exists());
+        assert!(!orphan_sst.exists());
+        assert!(!orphan_tmp.exists());
+        // The corrupted manifest doesn't stop the importer from starting.
+        assert!(corrupted.exists());
+
+        // The open engine is recovered with its write progress.
+        let engine = importer.bind_engine(uuid1).unwrap();
+        let manifest = engine.manifest();
+        assert_eq!(manifest.state, EngineState::Open);
+        assert_eq!(manifest.kv_count, 2);
+        drop(engine);
+        importer.open_engine(uuid1).unwrap();
+        importer.close_engine(uuid1).unwrap();
+
+        // The closed engine can not be opened again.
+        assert!(importer.bind_engine(uuid2).is_err());
+        assert!(importer.open_engine(uuid2).is_err());
+        let manifest: EngineManifest = load_json(&importer.dir.join(uuid2).manifest).unwrap();
+        assert_eq!(manifest.state, EngineState::Closed);
+
+        // The cleaned up engine is gone.
+        assert!(importer.bind_engine(uuid3).is_err());
+        assert!(!importer.dir.join(uuid3).manifest.exists());
+
+        // Engines written in an unsupported format are removed.
+        let (path1, path) = (importer.dir.join(uuid1), importer.dir.join(uuid2));
+        drop(importer);
+        let mut manifest: EngineManifest = load_json(&path.manifest).unwrap();
+        manifest.format_version = 0;
+        save_json(&path.manifest, &manifest).unwrap();
+        KVImporter::new(
+            cfg,
+            DbConfig::default(),
+            PdConfig::default(),
+            Arc::default(),
+            None,
+        )
+        .unwrap();
+        assert!(!path.manifest.exists());
+        assert!(!path.save.exists());
+        assert!(path1.manifest.exists());
+        assert!(path1.save.exists());
+    }
+
+    #[test]
+    fn test_reset_import_checkpoint() {
+        let temp_dir = TempDir::new("test_reset_import_checkpoint").unwrap();
+
+        let mut cfg = Config::default();
+        cfg.import_dir = temp_dir.path().to_str().unwrap().to_owned();
+        let importer = KVImporter::new(
+            cfg,
+            DbConfig::default(),
+            PdConfig::default(),
+            Arc::default(),
+            None,
+        )
+        .unwrap();
+
+        let uuid = Uuid::new_v4();
+        let checkpoint = importer.dir.checkpoint(uuid, 1).unwrap();
+        checkpoint.add(new_range(b"a", b"b")).unwrap();
+        let path = importer.dir.join(uuid).checkpoint;
+        assert!(path.exists());
+
+        // The import job is going to resume from the checkpoint.
+        let checkpoint = importer.dir.checkpoint(uuid, 1).unwrap();
+        assert_eq!(checkpoint.finished_ranges(), vec![new_range(b"a", b"b")]);
+
+        importer.reset_import_checkpoint(uuid).unwrap();
+        assert!(!path.exists());
+        let checkpoint = importer.dir.checkpoint(uuid, 1).unwrap();
+        assert!(checkpoint.finished_ranges().is_empty());
     }
 
     #[test]
     fn test_engine_file() {
+        test_engine_file_with(None);
+        let key_dir = TempDir::new("test_engine_file_key").unwrap();
+        test_engine_file_with(Some(new_key_manager(key_dir.path())));
+    }
+
+    fn test_engine_file_with(key_manager: Option<Arc<DataKeyManager>>) {
         let temp_dir = TempDir::new("test_engine_file").unwrap();
 
         let uuid = Uuid::new_v4();
         let db_cfg = DbConfig::default();
-        let security_mgr = Arc::<SecurityManager>::default();
         let path = EnginePath {
             save: temp_dir.path().join("save"),
             temp: temp_dir.path().join("temp"),
+            manifest: temp_dir.path().join("manifest"),
+            checkpoint: temp_dir.path().join("checkpoint"),
+            checksum: temp_dir.path().join("checksum"),
+        };
+        let new_engine_file = || {
+            EngineFile::new(
+                uuid,
+                path.clone(),
+                EngineManifest::default(),
+                db_cfg.clone(),
+                key_manager.clone(),
+                false,
+            )
         };
 
         // Test close.
         {
-            let mut f =
-                EngineFile::new(uuid, path.clone(), db_cfg.clone(), security_mgr.clone()).unwrap();
+            let mut f = new_engine_file().unwrap();
             // Cannot create the same file again.
-            assert!(
-                EngineFile::new(uuid, path.clone(), db_cfg.clone(), security_mgr.clone()).is_err()
-            );
+            assert!(new_engine_file().is_err());
+            let mut pair = KvPair::default();
+            pair.set_key(b"k".to_vec());
+            pair.set_value(b"v".to_vec());
+            f.write_v3(1, &[pair], "test").unwrap();
             assert!(path.temp.exists());
             assert!(!path.save.exists());
             f.close().unwrap();
             assert!(!path.temp.exists());
             assert!(path.save.exists());
-            fs::remove_dir_all(&path.save).unwrap();
+            let manifest: EngineManifest = load_json(&path.manifest).unwrap();
+            assert_eq!(manifest.state, EngineState::Closed);
+
+            // Files, including encrypted ones, are readable after moved.
+            let engine =
+                Engine::new(&path.save, uuid, db_cfg.clone(), key_manager.clone(), false).unwrap();
+            let key = Key::from_raw(b"k").append_ts(TimeStamp::new(1));
+            let value = engine.get(key.as_encoded()).unwrap().unwrap();
+            assert_eq!(EngineValue::decode(&value).unwrap(), EngineValue::Put(b"v"));
+            drop(engine);
+            remove_engine_dir(&path.save, key_manager.as_deref()).unwrap();
         }
 
-        // Test cleanup.
+        // Test drop.
         {
-            let f =
-                EngineFile::new(uuid, path.clone(), db_cfg.clone(), security_mgr.clone()).unwrap();
+            let f = new_engine_file().unwrap();
+            f.write_v3(0, &[KvPair::default()], "test").unwrap();
             assert!(path.temp.exists());
             assert!(!path.save.exists());
             drop(f);
-            assert!(!path.temp.exists());
+            // Files are kept for recovery.
+            assert!(path.temp.exists());
             assert!(!path.save.exists());
+            let manifest: EngineManifest = load_json(&path.manifest).unwrap();
+            assert_eq!(manifest.state, EngineState::Open);
+            assert_eq!(manifest.kv_count, 1);
         }
     }
 }
@@ -1,5 +1,6 @@
 // Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::fs;
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -7,9 +8,11 @@ use std::sync::Arc;
 use grpcio::{ChannelBuilder, EnvBuilder, Server as GrpcServer, ServerBuilder};
 use kvproto::import_kvpb::create_import_kv;
 
+use encryption_export::data_key_manager_from_config;
 use security::SecurityManager;
 use tikv_util::thd_name;
 
+use super::import_kv_extpb::create_import_kv_ext;
 use super::{ImportKVService, KVImporter, TiKvConfig};
 use crate::import::status_server::StatusServer;
 
@@ -26,14 +29,24 @@ impl ImportKVServer {
         let addr = SocketAddr::from_str(&cfg.addr).unwrap();
 
         let security_mgr = Arc::new(SecurityManager::new(&tikv.security).unwrap());
+        // The key dictionary of encrypted engine files is kept in the import
+        // directory, along with the engines.
+        fs::create_dir_all(&tikv.import.import_dir).unwrap();
+        let key_manager =
+            data_key_manager_from_config(&tikv.security.encryption, &tikv.import.import_dir)
+                .unwrap()
+                .map(Arc::new);
 
         let importer = KVImporter::new(
             tikv.import.clone(),
             tikv.rocksdb.clone(),
+            tikv.pd.clone(),
             security_mgr.clone(),
+            key_manager,
         )
         .unwrap();
-        let import_service = ImportKVService::new(tikv.import.clone(), Arc::new(importer));
+        let importer = Arc::new(importer);
+        let import_service = ImportKVService::new(tikv.import.clone(), Arc::clone(&importer));
 
         let env = Arc::new(
             EnvBuilder::new()
@@ -56,14 +69,15 @@ impl ImportKVServer {
                 addr.port(),
             )
             .channel_args(channel_args)
-            .register_service(create_import_kv(import_service))
+            .register_service(create_import_kv(import_service.clone()))
+            .register_service(create_import_kv_ext(import_service))
             .build()
             .unwrap();
 
         let status_server = tikv
             .status_server_address
             .as_ref()
-            .map(|address| StatusServer::new(address, tikv.security.clone()));
+            .map(|address| StatusServer::new(address, tikv.security.clone(), importer));
         ImportKVServer {
             grpc_server,
             status_server,
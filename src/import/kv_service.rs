@@ -13,16 +13,22 @@ use uuid::Uuid;
 use tikv_util::time::Instant;
 use txn_types::Key;
 
-use super::client::*;
+use super::checksum;
+use super::engine::EngineValue;
+use super::import_kv_extpb::*;
 use super::metrics::{self, *};
+use super::progress;
 use super::service::*;
-use super::{Config, Error, KVImporter};
+use super::{Config, Error, KVImporter, Result};
 use crate::send_rpc_response;
 
 #[derive(Clone)]
 pub struct ImportKVService {
     cfg: Config,
     threads: ThreadPool,
+    // Requests scanning whole engines are handled separately, so that they
+    // will not block writes.
+    scan_threads: ThreadPool,
     importer: Arc<KVImporter>,
 }
 
@@ -33,9 +39,15 @@ impl ImportKVService {
             .pool_size(cfg.num_threads)
             .create()
             .unwrap();
+        let scan_threads = ThreadPoolBuilder::new()
+            .name_prefix("kv-scanner")
+            .pool_size(cfg.num_scan_threads)
+            .create()
+            .unwrap();
         ImportKVService {
             cfg,
             threads,
+            scan_threads,
             importer,
         }
     }
@@ -70,7 +82,8 @@ macro_rules! try_engine {
 ///    the import process is not atomic, and it requires the data to be
 ///    idempotent on retry. An engine can only be imported after it is
 ///    closed. An engine can be imported multiple times, but can not be
-///    imported concurrently.
+///    imported concurrently. An import can be aborted by the
+///    `AbortImportEngine` request of `ImportKVExt`.
 /// 5. Cleans up the engine after it has been imported. Delete all data
 ///    in the engine. An engine can not be cleaned up when it is
 ///    writing or importing.
@@ -87,14 +100,12 @@ impl ImportKv for ImportKVService {
     ) {
         let label = "switch_mode";
         let timer = Instant::now_coarse();
-        let min_available_ratio = self.cfg.min_available_ratio;
-        let security_mgr = self.importer.security_mgr.clone();
+        let importer = Arc::clone(&self.importer);
 
         ctx.spawn(
             self.threads
                 .spawn_with_handle(async move {
-                    let client = Client::new(req.get_pd_addr(), 1, min_available_ratio, security_mgr).await?;
-                    match client.switch_cluster(req.get_request()).await {
+                    match importer.switch_mode(&req).await {
                         Ok(_) => {
                             info!("switch cluster"; "req" => ?req.get_request());
                             Ok(SwitchModeResponse::default())
@@ -144,6 +155,8 @@ impl ImportKv for ImportKVService {
         let label = "write_engine";
         let timer = Instant::now_coarse();
         let import = Arc::clone(&self.importer);
+        // Identifies the writer of duplicate keys.
+        let source = ctx.peer();
 
         ctx.spawn(
             self.threads
@@ -165,7 +178,7 @@ impl ImportKv for ImportKVService {
                             }
                             let start = Instant::now_coarse();
                             let batch = chunk.take_batch();
-                            let batch_size = engine.write(batch)?;
+                            let batch_size = engine.write(batch, &source)?;
                             IMPORT_WRITE_CHUNK_BYTES.observe(batch_size as f64);
                             IMPORT_WRITE_CHUNK_DURATION.observe(start.elapsed_secs());
                         }
@@ -187,6 +200,8 @@ impl ImportKv for ImportKVService {
         let label = "write_engine";
         let timer = Instant::now_coarse();
         let import = Arc::clone(&self.importer);
+        // Identifies the writer of duplicate keys.
+        let source = ctx.peer();
 
         ctx.spawn(
             self.threads
@@ -197,7 +212,7 @@ impl ImportKv for ImportKVService {
 
                         let ts = req.get_commit_ts();
                         let start = Instant::now_coarse();
-                        let write_size = engine.write_v3(ts, req.get_pairs())?;
+                        let write_size = engine.write_v3(ts, req.get_pairs(), &source)?;
                         IMPORT_WRITE_CHUNK_BYTES.observe(write_size as f64);
                         IMPORT_WRITE_CHUNK_DURATION.observe(start.elapsed_secs());
                         Ok(WriteEngineResponse::default())
@@ -290,8 +305,8 @@ impl ImportKv for ImportKVService {
     ) {
         let label = "compact_cluster";
         let timer = Instant::now_coarse();
-        let min_available_ratio = self.cfg.min_available_ratio;
-        let security_mgr = self.importer.security_mgr.clone();
+        let importer = Arc::clone(&self.importer);
+        let strict = self.cfg.strict_cluster_request;
 
         let mut compact = req.get_request().clone();
         if compact.has_range() {
@@ -310,10 +325,8 @@ impl ImportKv for ImportKVService {
             self.threads
                 .spawn_with_handle(
                     async move {
-                        let client =
-                            Client::new(req.get_pd_addr(), 1, min_available_ratio, security_mgr)
-                                .await?;
-                        match client.compact_cluster(&compact).await {
+                        let client = importer.client(req.get_pd_addr()).await?;
+                        match client.compact_cluster(&compact, strict).await {
                             Ok(_) => {
                                 info!("compact cluster"; "req" => ?compact);
                                 Ok(CompactClusterResponse::default())
@@ -379,3 +392,305 @@ impl ImportKv for ImportKVService {
         )
     }
 }
+
+/// ImportKVExt provides requests which are not defined by kvproto, errors
+/// are returned as gRPC errors.
+impl ImportKvExt for ImportKVService {
+    /// Writes puts and deletions to an engine. Unlike `WriteEngineV3`, keys
+    /// can be deleted explicitly.
+    fn write_engine_mutations(
+        &mut self,
+        ctx: RpcContext<'_>,
+        req: WriteEngineMutationsRequest,
+        sink: UnarySink<WriteEngineMutationsResponse>,
+    ) {
+        let label = "write_engine";
+        let timer = Instant::now_coarse();
+        let import = Arc::clone(&self.importer);
+        // Identifies the writer of duplicate keys.
+        let source = ctx.peer();
+
+        ctx.spawn(
+            self.threads
+                .spawn_with_handle(
+                    async move {
+                        let uuid = Uuid::from_slice(&req.uuid)?;
+                        let engine = import.bind_engine(uuid)?;
+
+                        let mutations = req
+                            .mutations
+                            .iter()
+                            .map(|m| {
+                                let value = match MutationOp::from_i32(m.op) {
+                                    Some(MutationOp::Put) => EngineValue::Put(&m.value),
+                                    Some(MutationOp::Delete) => EngineValue::Delete,
+                                    None => {
+                                        let msg = format!("unknown mutation op {}", m.op);
+                                        return Err(Error::InvalidProtoMessage(msg));
+                                    }
+                                };
+                                Ok((m.key.as_slice(), value))
+                            })
+                            .collect::<Result<Vec<_>>>()?;
+                        let start = Instant::now_coarse();
+                        let write_size =
+                            engine.write_mutations(req.commit_ts, &mutations, &source)?;
+                        IMPORT_WRITE_CHUNK_BYTES.observe(write_size as f64);
+                        IMPORT_WRITE_CHUNK_DURATION.observe(start.elapsed_secs());
+                        Ok(WriteEngineMutationsResponse::default())
+                    }
+                    .then(move |res| send_rpc_response!(res, sink, label, timer)),
+                )
+                .unwrap(),
+        )
+    }
+
+    /// Resets the import checkpoint of an engine, so that the next import
+    /// uploads all data of the engine again, e.g. after the target cluster
+    /// is restored.
+    fn reset_import_checkpoint(
+        &mut self,
+        ctx: RpcContext<'_>,
+        req: ResetImportCheckpointRequest,
+        sink: UnarySink<ResetImportCheckpointResponse>,
+    ) {
+        let label = "reset_import_checkpoint";
+        let timer = Instant::now_coarse();
+        let import = Arc::clone(&self.importer);
+
+        ctx.spawn(
+            self.threads
+                .spawn_with_handle(
+                    async move {
+                        let uuid = Uuid::from_slice(&req.uuid)?;
+                        import.reset_import_checkpoint(uuid)?;
+                        Ok(ResetImportCheckpointResponse::default())
+                    }
+                    .then(move |res| send_rpc_response!(res, sink, label, timer)),
+                )
+                .unwrap(),
+        )
+    }
+
+    /// Imports an engine, the response is sent once the import job is
+    /// started if `async_import` is set.
+    fn import_engine_ext(
+        &mut self,
+        ctx: RpcContext<'_>,
+        req: ImportEngineExtRequest,
+        sink: UnarySink<ImportEngineExtResponse>,
+    ) {
+        let label = "import_engine_ext";
+        let timer = Instant::now_coarse();
+        let import = Arc::clone(&self.importer);
+        let threads = self.threads.clone();
+
+        ctx.spawn(
+            self.threads
+                .spawn_with_handle(
+                    async move {
+                        let uuid = Uuid::from_slice(&req.uuid)?;
+                        let job = import.new_import_job(uuid, &req.pd_addr).await?;
+                        if req.async_import {
+                            // The result of the job can be found in its status.
+                            threads
+                                .spawn(async move {
+                                    let _ = import.run_import_job(uuid, job).await;
+                                })
+                                .unwrap();
+                        } else {
+                            import.run_import_job(uuid, job).await?;
+                        }
+                        Ok(ImportEngineExtResponse::default())
+                    }
+                    .then(move |res| send_rpc_response!(res, sink, label, timer)),
+                )
+                .unwrap(),
+        )
+    }
+
+    /// Returns the progress of the running or finished import job of an
+    /// engine, which is useful for imports started asynchronously.
+    fn import_status(
+        &mut self,
+        ctx: RpcContext<'_>,
+        req: ImportStatusRequest,
+        sink: UnarySink<ImportStatusResponse>,
+    ) {
+        let label = "import_status";
+        let timer = Instant::now_coarse();
+        let import = Arc::clone(&self.importer);
+
+        ctx.spawn(
+            self.threads
+                .spawn_with_handle(
+                    async move {
+                        let uuid = Uuid::from_slice(&req.uuid)?;
+                        let status = import.import_status(uuid)?;
+                        let phase = match status.phase {
+                            progress::ImportPhase::Prepare => ImportPhase::Prepare,
+                            progress::ImportPhase::Import => ImportPhase::Import,
+                            progress::ImportPhase::Finished => ImportPhase::Finished,
+                        };
+                        Ok(ImportStatusResponse {
+                            phase: phase as i32,
+                            ranges_total: status.ranges_total as u64,
+                            ranges_finished: status.ranges_finished as u64,
+                            ranges_retrying: status.ranges_retrying as u64,
+                            bytes_uploaded: status.bytes_uploaded,
+                            error: status.error.unwrap_or_default(),
+                        })
+                    }
+                    .then(move |res| send_rpc_response!(res, sink, label, timer)),
+                )
+                .unwrap(),
+        )
+    }
+
+    /// Aborts the running import job of an engine, and waits for the job to
+    /// stop.
+    fn abort_import_engine(
+        &mut self,
+        ctx: RpcContext<'_>,
+        req: AbortImportEngineRequest,
+        sink: UnarySink<AbortImportEngineResponse>,
+    ) {
+        let label = "abort_import_engine";
+        let timer = Instant::now_coarse();
+        let import = Arc::clone(&self.importer);
+
+        ctx.spawn(
+            self.threads
+                .spawn_with_handle(
+                    async move {
+                        let uuid = Uuid::from_slice(&req.uuid)?;
+                        import.abort_import_engine(uuid).await?;
+                        Ok(AbortImportEngineResponse::default())
+                    }
+                    .then(move |res| send_rpc_response!(res, sink, label, timer)),
+                )
+                .unwrap(),
+        )
+    }
+
+    /// Returns the duplicate keys detected while writing an engine.
+    fn duplicate_keys(
+        &mut self,
+        ctx: RpcContext<'_>,
+        req: DuplicateKeysRequest,
+        sink: UnarySink<DuplicateKeysResponse>,
+    ) {
+        let label = "duplicate_keys";
+        let timer = Instant::now_coarse();
+        let import = Arc::clone(&self.importer);
+
+        ctx.spawn(
+            self.scan_threads
+                .spawn_with_handle(
+                    async move {
+                        let uuid = Uuid::from_slice(&req.uuid)?;
+                        let to_mutation = |value: Option<Vec<u8>>| match value {
+                            Some(v) => (MutationOp::Put as i32, v),
+                            None => (MutationOp::Delete as i32, Vec::new()),
+                        };
+                        let keys = import
+                            .duplicate_keys(uuid)?
+                            .into_iter()
+                            .map(|dup| {
+                                let (old_op, old_value) = to_mutation(dup.old_value);
+                                let (new_op, new_value) = to_mutation(dup.new_value);
+                                DuplicateKey {
+                                    key: dup.key,
+                                    commit_ts: dup.commit_ts,
+                                    old_op,
+                                    old_value,
+                                    old_source: dup.old_source,
+                                    new_op,
+                                    new_value,
+                                    new_source: dup.new_source,
+                                }
+                            })
+                            .collect();
+                        Ok(DuplicateKeysResponse { keys })
+                    }
+                    .then(move |res| send_rpc_response!(res, sink, label, timer)),
+                )
+                .unwrap(),
+        )
+    }
+
+    /// Returns the checksum of a closed engine.
+    fn checksum_engine(
+        &mut self,
+        ctx: RpcContext<'_>,
+        req: ChecksumEngineRequest,
+        sink: UnarySink<ChecksumEngineResponse>,
+    ) {
+        let label = "checksum_engine";
+        let timer = Instant::now_coarse();
+        let import = Arc::clone(&self.importer);
+
+        ctx.spawn(
+            self.scan_threads
+                .spawn_with_handle(
+                    async move {
+                        let uuid = Uuid::from_slice(&req.uuid)?;
+                        let checksum = import.checksum_engine(uuid, req.per_table)?;
+                        let to_pb = |c: checksum::Checksum| Checksum {
+                            crc64_xor: c.crc64_xor,
+                            total_kvs: c.total_kvs,
+                            total_bytes: c.total_bytes,
+                        };
+                        let tables = checksum
+                            .tables
+                            .into_iter()
+                            .map(|(table_id, c)| TableChecksum {
+                                table_id,
+                                checksum: Some(to_pb(c)),
+                            })
+                            .collect();
+                        Ok(ChecksumEngineResponse {
+                            total: Some(to_pb(checksum.total)),
+                            tables,
+                        })
+                    }
+                    .then(move |res| send_rpc_response!(res, sink, label, timer)),
+                )
+                .unwrap(),
+        )
+    }
+
+    /// Returns the statistics of an engine.
+    fn engine_stats(
+        &mut self,
+        ctx: RpcContext<'_>,
+        req: EngineStatsRequest,
+        sink: UnarySink<EngineStatsResponse>,
+    ) {
+        let label = "engine_stats";
+        let timer = Instant::now_coarse();
+        let import = Arc::clone(&self.importer);
+
+        ctx.spawn(
+            self.scan_threads
+                .spawn_with_handle(
+                    async move {
+                        let uuid = Uuid::from_slice(&req.uuid)?;
+                        let stats = import.engine_stats(uuid)?;
+                        Ok(EngineStatsResponse {
+                            approximate_kvs: stats.approximate_kvs,
+                            kvs: stats.kvs,
+                            deleted_kvs: stats.deleted_kvs,
+                            total_size: stats.total_size,
+                            smallest_key: stats.smallest_key,
+                            largest_key: stats.largest_key,
+                            sst_files: stats.sst_files,
+                            commit_ts: stats.commit_ts,
+                        })
+                    }
+                    .then(move |res| send_rpc_response!(res, sink, label, timer)),
+                )
+                .unwrap(),
+        )
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_channel::{bounded, Receiver, Sender};
+use prometheus::Gauge;
+
+use collections::HashMap;
+use tikv_util::config::MB;
+use tikv_util::time::Limiter;
+
+use super::metrics::*;
+use super::Config;
+
+// The upload rate of a busy store can not be lowered below this.
+const MIN_STORE_SPEED_LIMIT: f64 = MB as f64;
+// The upload rate of a store recovers by this ratio of its max rate on
+// every success.
+const STORE_SPEED_RECOVER_RATIO: f64 = 0.1;
+const BUSY_BACKOFF_MILLIS: u64 = 1000;
+const MAX_BUSY_BACKOFF_MILLIS: u64 = 30000;
+
+/// UploadLimiter restricts the upload rate of the whole cluster and of each
+/// store, and the number of concurrent uploads and ingests of each store, so
+/// that a slow store doesn't get more requests than it can handle.
+///
+/// The upload rate of a store is lowered when the store reports that it is
+/// busy, and recovered step by step when it succeeds again.
+pub struct UploadLimiter {
+    speed_limit: Limiter,
+    store_speed_limit: f64,
+    max_store_uploads: usize,
+    max_store_ingests: usize,
+    stores: Mutex<HashMap<u64, Arc<StoreLimiter>>>,
+}
+
+impl UploadLimiter {
+    pub fn new(cfg: &Config) -> UploadLimiter {
+        // A store can not upload faster than the whole cluster.
+        let store_speed_limit = match cfg.store_upload_speed_limit.0 {
+            0 => cfg.upload_speed_limit.0,
+            v => v.min(cfg.upload_speed_limit.0),
+        } as f64;
+        UploadLimiter {
+            speed_limit: Limiter::new(cfg.upload_speed_limit.0 as f64),
+            store_speed_limit,
+            max_store_uploads: cfg.max_store_concurrent_uploads,
+            max_store_ingests: cfg.max_store_concurrent_ingests,
+            stores: Mutex::new(HashMap::default()),
+        }
+    }
+
+    fn store(&self, store_id: u64) -> Arc<StoreLimiter> {
+        let mut stores = self.stores.lock().unwrap();
+        let store = stores.entry(store_id).or_insert_with(|| {
+            Arc::new(StoreLimiter::new(
+                store_id,
+                self.store_speed_limit,
+                self.max_store_uploads,
+                self.max_store_ingests,
+            ))
+        });
+        Arc::clone(store)
+    }
+
+    /// Waits until `size` bytes can be uploaded to the store. The returned
+    /// permit must be held until the upload finishes.
+    pub async fn acquire_upload(&self, store_id: u64, size: u64) -> Permit {
+        let store = self.store(store_id);
+        let permit = store.uploads.acquire().await;
+        self.speed_limit.consume(size as usize).await;
+        store.speed_limit.consume(size as usize).await;
+        permit
+    }
+
+    /// Waits until an ingest can be sent to the store. The returned permit
+    /// must be held until the ingest finishes.
+    pub async fn acquire_ingest(&self, store_id: u64) -> Permit {
+        self.store(store_id).ingests.acquire().await
+    }
+
+    /// Lowers the upload rate of the busy store, returns how long we should
+    /// wait before sending requests to the store again.
+    pub fn on_server_busy(&self, store_id: u64, backoff_ms: u64) -> Duration {
+        let store = self.store(store_id);
+        let speed_limit = (store.speed_limit.speed_limit() / 2.0).max(MIN_STORE_SPEED_LIMIT);
+        store.set_speed_limit(speed_limit);
+
+        let mut busy_backoff_ms = store.busy_backoff_ms.lock().unwrap();
+        *busy_backoff_ms = match *busy_backoff_ms {
+            0 => BUSY_BACKOFF_MILLIS,
+            v => (2 * v).min(MAX_BUSY_BACKOFF_MILLIS),
+        };
+        // Respect the backoff suggested by the store.
+        let backoff_ms = backoff_ms.max(*busy_backoff_ms);
+        warn!("store is busy, slow down uploading"; "store" => %store_id, "speed_limit" => %speed_limit, "backoff_ms" => %backoff_ms);
+        Duration::from_millis(backoff_ms)
+    }
+
+    /// Recovers the upload rate of the store step by step.
+    pub fn on_success(&self, store_id: u64) {
+        let store = self.store(store_id);
+        *store.busy_backoff_ms.lock().unwrap() = 0;
+        let current = store.speed_limit.speed_limit();
+        if current < store.max_speed_limit {
+            let speed_limit = (current + store.max_speed_limit * STORE_SPEED_RECOVER_RATIO)
+                .min(store.max_speed_limit);
+            store.set_speed_limit(speed_limit);
+        }
+    }
+}
+
+struct StoreLimiter {
+    label: String,
+    max_speed_limit: f64,
+    speed_limit: Limiter,
+    busy_backoff_ms: Mutex<u64>,
+    uploads: Semaphore,
+    ingests: Semaphore,
+}
+
+impl StoreLimiter {
+    fn new(
+        store_id: u64,
+        speed_limit: f64,
+        max_uploads: usize,
+        max_ingests: usize,
+    ) -> StoreLimiter {
+        let label = store_id.to_string();
+        let gauge = |ty| IMPORT_STORE_LIMIT.with_label_values(&[label.as_str(), ty]);
+        gauge("speed_limit").set(speed_limit);
+        gauge("max_uploads").set(max_uploads as f64);
+        gauge("max_ingests").set(max_ingests as f64);
+        StoreLimiter {
+            max_speed_limit: speed_limit,
+            speed_limit: Limiter::new(speed_limit),
+            busy_backoff_ms: Mutex::new(0),
+            uploads: Semaphore::new(max_uploads, gauge("uploads")),
+            ingests: Semaphore::new(max_ingests, gauge("ingests")),
+            label,
+        }
+    }
+
+    fn set_speed_limit(&self, speed_limit: f64) {
+        self.speed_limit.set_speed_limit(speed_limit);
+        IMPORT_STORE_LIMIT
+            .with_label_values(&[self.label.as_str(), "speed_limit"])
+            .set(speed_limit);
+    }
+}
+
+/// Semaphore limits the number of concurrent tasks, the number of running
+/// tasks is exported by `gauge`.
+struct Semaphore {
+    tx: Sender<()>,
+    rx: Receiver<()>,
+    gauge: Gauge,
+}
+
+impl Semaphore {
+    fn new(capacity: usize, gauge: Gauge) -> Semaphore {
+        let (tx, rx) = bounded(capacity);
+        gauge.set(0.0);
+        Semaphore { tx, rx, gauge }
+    }
+
+    async fn acquire(&self) -> Permit {
+        // The channel is never closed because we hold the receiver.
+        self.tx.send(()).await.unwrap();
+        self.gauge.set(self.rx.len() as f64);
+        Permit {
+            rx: self.rx.clone(),
+            gauge: self.gauge.clone(),
+        }
+    }
+}
+
+/// Permit releases the acquired slot on drop.
+pub struct Permit {
+    rx: Receiver<()>,
+    gauge: Gauge,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let _ = self.rx.try_recv();
+        self.gauge.set(self.rx.len() as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+    use futures::future::FutureExt;
+    use tikv_util::config::ReadableSize;
+
+    #[test]
+    fn test_upload_limiter() {
+        let mut cfg = Config::default();
+        cfg.max_store_concurrent_uploads = 2;
+        cfg.max_store_concurrent_ingests = 1;
+        let limiter = UploadLimiter::new(&cfg);
+
+        let p1 = block_on(limiter.acquire_upload(1, 1));
+        let p2 = block_on(limiter.acquire_upload(1, 1));
+        // Store 1 is full, but store 2 is not affected.
+        assert!(limiter.acquire_upload(1, 1).now_or_never().is_none());
+        let p3 = block_on(limiter.acquire_upload(2, 1));
+        drop(p1);
+        let p4 = block_on(limiter.acquire_upload(1, 1));
+        drop((p2, p3, p4));
+
+        let p = block_on(limiter.acquire_ingest(1));
+        assert!(limiter.acquire_ingest(1).now_or_never().is_none());
+        drop(p);
+        block_on(limiter.acquire_ingest(1));
+    }
+
+    #[test]
+    fn test_upload_limiter_on_server_busy() {
+        let mut cfg = Config::default();
+        cfg.upload_speed_limit = ReadableSize::mb(100);
+        cfg.store_upload_speed_limit = ReadableSize::mb(10);
+        let limiter = UploadLimiter::new(&cfg);
+        let speed_limit = |id| limiter.store(id).speed_limit.speed_limit();
+        let max = ReadableSize::mb(10).0 as f64;
+        assert_eq!(speed_limit(1), max);
+
+        // Slow down and back off exponentially.
+        let backoff = limiter.on_server_busy(1, 0);
+        assert_eq!(backoff, Duration::from_millis(BUSY_BACKOFF_MILLIS));
+        assert_eq!(speed_limit(1), max / 2.0);
+        let backoff = limiter.on_server_busy(1, 0);
+        assert_eq!(backoff, Duration::from_millis(BUSY_BACKOFF_MILLIS * 2));
+        assert_eq!(speed_limit(1), max / 4.0);
+        // The backoff suggested by the store is respected.
+        let backoff = limiter.on_server_busy(1, MAX_BUSY_BACKOFF_MILLIS * 2);
+        assert_eq!(backoff, Duration::from_millis(MAX_BUSY_BACKOFF_MILLIS * 2));
+        for _ in 0..16 {
+            limiter.on_server_busy(1, 0);
+        }
+        assert_eq!(speed_limit(1), MIN_STORE_SPEED_LIMIT);
+        // Other stores are not affected.
+        assert_eq!(speed_limit(2), max);
+
+        // Recover step by step.
+        limiter.on_success(1);
+        assert!(speed_limit(1) > MIN_STORE_SPEED_LIMIT);
+        assert!(speed_limit(1) < max);
+        for _ in 0..10 {
+            limiter.on_success(1);
+        }
+        assert_eq!(speed_limit(1), max);
+        let backoff = limiter.on_server_busy(1, 0);
+        assert_eq!(backoff, Duration::from_millis(BUSY_BACKOFF_MILLIS));
+    }
+}
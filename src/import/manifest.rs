@@ -0,0 +1,320 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use kvproto::import_sstpb::Range;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::common::*;
+use super::{Error, Result};
+
+/// The lifecycle state of an engine.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EngineState {
+    /// The engine is being written in the temp directory.
+    Open,
+    /// The engine has been closed and moved to the save directory.
+    Closed,
+    /// The engine is being imported to a cluster.
+    Importing,
+    /// The engine has been imported to a cluster successfully.
+    Imported,
+}
+
+impl Default for EngineState {
+    fn default() -> EngineState {
+        EngineState::Open
+    }
+}
+
+/// The version of the format of KV pairs stored in engines. Engines written
+/// in other formats can not be recovered.
+pub const ENGINE_FORMAT_VERSION: u32 = 1;
+
+/// EngineManifest records the state and the write progress of an engine, so
+/// that the engine can be recovered after the importer restarts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct EngineManifest {
+    /// The format version of the engine, which is 0 for manifests written
+    /// before the version is recorded.
+    #[serde(default)]
+    pub format_version: u32,
+    pub state: EngineState,
+    /// Number of KV pairs persisted in the engine.
+    pub kv_count: u64,
+    /// Size of write batches persisted in the engine.
+    pub write_bytes: u64,
+}
+
+impl Default for EngineManifest {
+    fn default() -> EngineManifest {
+        EngineManifest {
+            format_version: ENGINE_FORMAT_VERSION,
+            state: EngineState::default(),
+            kv_count: 0,
+            write_bytes: 0,
+        }
+    }
+}
+
+/// ImportCheckpoint records the ranges of an engine which have been imported
+/// to a cluster, so that a failed or interrupted import to the same cluster
+/// can be resumed from them.
+pub struct ImportCheckpoint {
+    path: PathBuf,
+    cluster_id: u64,
+    finished_ranges: Mutex<Vec<Range>>,
+}
+
+impl ImportCheckpoint {
+    /// Loads the checkpoint of importing to `cluster_id` from `path`. An
+    /// empty checkpoint is returned if the file doesn't exist, or if it is
+    /// recorded for another cluster.
+    pub fn load(path: PathBuf, cluster_id: u64) -> Result<ImportCheckpoint> {
+        let mut finished_ranges = Vec::new();
+        if path.exists() {
+            let file: CheckpointFile = load_json(&path)?;
+            if file.cluster_id == cluster_id {
+                finished_ranges = file
+                    .finished_ranges
+                    .into_iter()
+                    .map(|r| new_range(&r.start, &r.end))
+                    .collect();
+            } else {
+                info!("ignore import checkpoint of another cluster"; "path" => ?path, "cluster_id" => file.cluster_id, "expected" => cluster_id);
+            }
+        }
+        Ok(ImportCheckpoint {
+            path,
+            cluster_id,
+            finished_ranges: Mutex::new(finished_ranges),
+        })
+    }
+
+    pub fn finished_ranges(&self) -> Vec<Range> {
+        self.finished_ranges.lock().unwrap().clone()
+    }
+
+    /// Adds a finished range and persists the checkpoint.
+    pub fn add(&self, range: Range) -> Result<()> {
+        let mut finished_ranges = self.finished_ranges.lock().unwrap();
+        finished_ranges.push(range);
+        merge_ranges(&mut finished_ranges);
+        let file = CheckpointFile {
+            cluster_id: self.cluster_id,
+            finished_ranges: finished_ranges
+                .iter()
+                .map(|r| CheckpointRange {
+                    start: r.get_start().to_owned(),
+                    end: r.get_end().to_owned(),
+                })
+                .collect(),
+        };
+        save_json(&self.path, &file)
+    }
+
+    /// Forgets all finished ranges and removes the checkpoint file.
+    pub fn reset(&self) -> Result<()> {
+        let mut finished_ranges = self.finished_ranges.lock().unwrap();
+        finished_ranges.clear();
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+struct CheckpointFile {
+    cluster_id: u64,
+    finished_ranges: Vec<CheckpointRange>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CheckpointRange {
+    #[serde(with = "hex_bytes")]
+    start: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    end: Vec<u8>,
+}
+
+/// Serializes bytes as a hex string.
+pub mod hex_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(v: &[u8], s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.serialize_str(&hex::encode(v))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        hex::decode(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serializes optional bytes as a hex string or null.
+pub mod opt_hex_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        v: &Option<Vec<u8>>,
+        s: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        match v {
+            Some(v) => s.serialize_some(&hex::encode(v)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> std::result::Result<Option<Vec<u8>>, D::Error> {
+        match Option::<String>::deserialize(d)? {
+            Some(s) => hex::decode(&s).map(Some).map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Sorts `ranges` and merges overlapping or adjacent ones.
+fn merge_ranges(ranges: &mut Vec<Range>) {
+    ranges.sort_by(|a, b| a.get_start().cmp(b.get_start()));
+    let mut merged: Vec<Range> = Vec::with_capacity(ranges.len());
+    for range in ranges.drain(..) {
+        if let Some(last) = merged.last_mut() {
+            if before_end(range.get_start(), last.get_end()) || range.get_start() == last.get_end()
+            {
+                if last.get_end() != RANGE_MAX && before_end(last.get_end(), range.get_end()) {
+                    last.set_end(range.get_end().to_owned());
+                }
+                continue;
+            }
+        }
+        merged.push(range);
+    }
+    *ranges = merged;
+}
+
+/// Loads a JSON file written by `save_json`.
+pub fn load_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let data = fs::read(path)?;
+    serde_json::from_slice(&data).map_err(|e| Error::FileCorrupted {
+        path: path.to_owned(),
+        reason: e.to_string(),
+    })
+}
+
+/// Saves `value` to `path` as JSON atomically, so that a crash will never
+/// leave a partially written file behind.
+pub fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let data = serde_json::to_vec(value).map_err(io::Error::from)?;
+    let mut temp_path = OsString::from(path);
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    {
+        let mut f = File::create(&temp_path)?;
+        f.write_all(&data)?;
+        f.sync_all()?;
+    }
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_engine_manifest() {
+        let temp_dir = TempDir::new("test_engine_manifest").unwrap();
+        let path = temp_dir.path().join("engine.manifest");
+
+        assert!(load_json::<EngineManifest>(&path).is_err());
+
+        let manifest = EngineManifest {
+            state: EngineState::Importing,
+            kv_count: 1,
+            write_bytes: 2,
+            ..Default::default()
+        };
+        assert_eq!(manifest.format_version, ENGINE_FORMAT_VERSION);
+        save_json(&path, &manifest).unwrap();
+        assert_eq!(load_json::<EngineManifest>(&path).unwrap(), manifest);
+        let data = fs::read_to_string(&path).unwrap();
+        assert!(data.contains("\"state\":\"importing\""));
+
+        // Manifests written before the format version is recorded.
+        fs::write(&path, b"{\"state\":\"closed\"}").unwrap();
+        let manifest = load_json::<EngineManifest>(&path).unwrap();
+        assert_eq!(manifest.format_version, 0);
+        assert_eq!(manifest.state, EngineState::Closed);
+
+        fs::write(&path, b"{").unwrap();
+        match load_json::<EngineManifest>(&path) {
+            Err(Error::FileCorrupted { .. }) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_merge_ranges() {
+        let mut ranges = vec![
+            new_range(b"c", b"d"),
+            new_range(b"a", b"b"),
+            new_range(b"b", b"c"),
+            new_range(b"e", b"f"),
+            new_range(b"g", b"i"),
+            new_range(b"h", b"j"),
+            new_range(b"x", RANGE_MAX),
+            new_range(b"y", b"z"),
+        ];
+        merge_ranges(&mut ranges);
+        let expected = vec![
+            new_range(b"a", b"d"),
+            new_range(b"e", b"f"),
+            new_range(b"g", b"j"),
+            new_range(b"x", RANGE_MAX),
+        ];
+        assert_eq!(ranges, expected);
+    }
+
+    #[test]
+    fn test_import_checkpoint() {
+        let temp_dir = TempDir::new("test_import_checkpoint").unwrap();
+        let path = temp_dir.path().join("engine.checkpoint");
+
+        let checkpoint = ImportCheckpoint::load(path.clone(), 1).unwrap();
+        assert!(checkpoint.finished_ranges().is_empty());
+        checkpoint.add(new_range(b"\x00", b"\x01")).unwrap();
+        checkpoint.add(new_range(b"\x01", b"\xff")).unwrap();
+        checkpoint.add(new_range(b"\xff\xff", RANGE_MAX)).unwrap();
+
+        let expected = vec![
+            new_range(b"\x00", b"\xff"),
+            new_range(b"\xff\xff", RANGE_MAX),
+        ];
+        let checkpoint = ImportCheckpoint::load(path.clone(), 1).unwrap();
+        assert_eq!(checkpoint.finished_ranges(), expected);
+        // Ranges imported to another cluster are not finished.
+        let other = ImportCheckpoint::load(path.clone(), 2).unwrap();
+        assert!(other.finished_ranges().is_empty());
+
+        checkpoint.reset().unwrap();
+        assert!(checkpoint.finished_ranges().is_empty());
+        assert!(!path.exists());
+        let checkpoint = ImportCheckpoint::load(path, 1).unwrap();
+        assert!(checkpoint.finished_ranges().is_empty());
+    }
+}
@@ -83,6 +83,12 @@ lazy_static! {
         &["phase"]
     )
     .unwrap();
+    pub static ref IMPORT_JOB_PROGRESS: GaugeVec = register_gauge_vec!(
+        "tikv_import_job_progress",
+        "Progress of import jobs",
+        &["uuid", "type"]
+    )
+    .unwrap();
     pub static ref IMPORT_STORE_SAPCE_NOT_ENOUGH_COUNTER: IntCounterVec =
         register_int_counter_vec!(
             "tikv_import_wait_store_available_count",
@@ -90,6 +96,18 @@ lazy_static! {
             &["store_id"]
         )
         .unwrap();
+    pub static ref IMPORT_STORE_LIMIT: GaugeVec = register_gauge_vec!(
+        "tikv_import_store_limit",
+        "Upload limits and running uploads/ingests of each store",
+        &["store_id", "type"]
+    )
+    .unwrap();
+    pub static ref IMPORT_SCATTER_REGION_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "tikv_import_scatter_region_count",
+        "Counter of regions waited for scattering",
+        &["result"]
+    )
+    .unwrap();
 }
 
 pub fn dump() -> String {
@@ -1,16 +1,24 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+mod checksum;
 mod client;
 mod common;
 mod config;
+mod duplicate;
 mod engine;
 mod errors;
 mod import;
+pub mod import_kv_extpb;
+mod import_mode;
 mod kv_importer;
 mod kv_server;
 mod kv_service;
+mod limiter;
+mod manifest;
 mod metrics;
 mod prepare;
+mod progress;
+mod region_cache;
 mod service;
 mod status_server;
 mod stream;
@@ -18,8 +26,8 @@ mod stream;
 #[cfg(test)]
 mod test_helpers;
 
-pub(crate) use config::Config;
 pub use config::TiKvConfig;
+pub(crate) use config::{Config, RetryConfig};
 pub(crate) use errors::{Error, Result};
 pub(crate) use kv_importer::KVImporter;
 pub use kv_server::ImportKVServer;
@@ -2,8 +2,10 @@
 
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
+use futures::future;
+use futures::stream::{self, StreamExt};
 use futures_timer::Delay;
 
 use engine_rocks::SizeProperties;
@@ -14,22 +16,16 @@ use super::client::*;
 use super::common::*;
 use super::engine::*;
 use super::metrics::*;
-use super::{Config, Error, Result};
+use super::progress::ImportProgress;
+use super::{Config, Error, Result, RetryConfig};
 
-const MAX_RETRY_TIMES: u64 = 3;
-const RETRY_INTERVAL_SECS: u64 = 1;
-
-const SPLIT_WAIT_MAX_RETRY_TIMES: u64 = 64;
-const SPLIT_WAIT_INTERVAL_MILLIS: u64 = 8;
-const SPLIT_MAX_WAIT_INTERVAL_MILLIS: u64 = 1000;
-const SCATTER_WAIT_MAX_RETRY_TIMES: u64 = 128;
-const SCATTER_WAIT_INTERVAL_MILLIS: u64 = 50;
-const SCATTER_MAX_WAIT_INTERVAL_MILLIS: u64 = 5000;
+// The max number of regions checked whether scattering is finished at the
+// same time.
+const MAX_CONCURRENT_SCATTER_CHECKS: usize = 16;
 
 macro_rules! exec_with_retry {
-    ($tag:expr, $func:expr, $times:expr, $interval:expr, $max_duration:expr) => {
+    ($tag:expr, $func:expr, $times:expr, $backoff:expr) => {
         let start = Instant::now();
-        let mut interval = $interval;
         for i in 0..$times {
             if $func {
                 if i > 0 {
@@ -40,8 +36,7 @@ macro_rules! exec_with_retry {
                 warn!(concat!($tag, " still failed after exhausting all retries"));
             } else {
                 // Exponential back-off with max wait duration
-                interval = (2 * interval).min($max_duration);
-                Delay::new(Duration::from_millis(interval)).await;
+                Delay::new(($backoff)(i + 1)).await;
             }
         }
     };
@@ -57,16 +52,23 @@ pub struct PrepareJob<Client> {
     cfg: Config,
     client: Arc<Client>,
     engine: Arc<Engine>,
+    progress: Arc<ImportProgress>,
     counter: AtomicUsize,
 }
 
 impl<Client: ImportClient> PrepareJob<Client> {
-    pub fn new(cfg: Config, client: Client, engine: Arc<Engine>) -> PrepareJob<Client> {
+    pub fn new(
+        cfg: Config,
+        client: Client,
+        engine: Arc<Engine>,
+        progress: Arc<ImportProgress>,
+    ) -> PrepareJob<Client> {
         PrepareJob {
             tag: format!("[PrepareJob {}]", engine.uuid()),
             cfg,
             client: Arc::new(client),
             engine,
+            progress,
             counter: AtomicUsize::new(0),
         }
     }
@@ -106,78 +108,162 @@ impl<Client: ImportClient> PrepareJob<Client> {
         let split_size = self.cfg.region_split_size.0 as usize;
         let mut ctx = RangeContext::new(Arc::clone(&self.client), split_size);
 
-        let mut wait_scatter_regions = vec![];
-        let mut num_prepares = 0;
-        let mut start = Vec::new();
+        // Preparing is only an optimization, the import can go on without
+        // it, so we stop preparing once the deadline exceeded.
+        let deadline = Instant::now() + self.cfg.max_prepare_duration.0;
+
+        // Collect the split keys of all ranges first, so that keys in the
+        // same region can be split in one request.
+        let mut split_keys = Vec::new();
         for (k, v) in props.index_handles.iter() {
+            if self.progress.is_aborted() {
+                return Err(Error::ImportJobAborted(self.engine.uuid()));
+            }
+            // Locating the regions of split keys requests PD, which is
+            // bounded by the deadline too.
+            if Instant::now() >= deadline {
+                warn!("prepare timeout, skip collecting the rest split keys"; "tag" => %self.tag, "split_keys" => %split_keys.len(), "max_prepare_duration" => ?self.cfg.max_prepare_duration);
+                break;
+            }
             ctx.add(v.size as usize);
             if !ctx.should_stop_before(k) {
                 continue;
             }
+            split_keys.push(k.to_owned());
+            ctx.reset(k).await;
+        }
+        // The rest data after the last split key makes up the last range.
+        let mut num_ranges = split_keys.len();
+        if ctx.raw_size() > 0 {
+            num_ranges += 1;
+        }
+        self.progress.set_ranges_total(num_ranges);
 
-            let range = RangeInfo::new(&start, k, ctx.raw_size());
-            if let Ok(true) = self
-                .run_prepare_range_job(range, &mut wait_scatter_regions)
+        let mut wait_scatter_regions = vec![];
+        let mut rest_keys = split_keys.as_slice();
+        while !rest_keys.is_empty() {
+            if self.progress.is_aborted() {
+                return Err(Error::ImportJobAborted(self.engine.uuid()));
+            }
+            if Instant::now() >= deadline {
+                warn!("prepare timeout, skip the rest ranges"; "tag" => %self.tag, "prepared_ranges" => %wait_scatter_regions.len(), "rest_ranges" => %rest_keys.len(), "max_prepare_duration" => ?self.cfg.max_prepare_duration);
+                break;
+            }
+            // Limit the size of one split request, which is bounded by the
+            // gRPC timeout.
+            let batch = &rest_keys[..rest_keys.len().min(self.cfg.max_split_keys_per_request)];
+            match self
+                .run_prepare_region_job(batch, &mut wait_scatter_regions)
                 .await
             {
-                num_prepares += 1;
+                Ok(n) => rest_keys = &rest_keys[n..],
+                // Skip the whole batch which can not be prepared, retrying
+                // the rest keys of the batch is likely to fail again.
+                Err(_) => rest_keys = &rest_keys[batch.len()..],
             }
-
-            start = k.to_owned();
-            ctx.reset(k).await;
         }
+        // Each split key results in a new region to scatter.
+        let num_prepares = wait_scatter_regions.len();
+        self.wait_scatter_regions(wait_scatter_regions, deadline)
+            .await?;
+
+        Ok(num_prepares)
+    }
 
-        // We need to wait all regions for scattering finished.
+    /// Waits for all regions for scattering finished. All unfinished regions
+    /// are checked in each round, so that the wait time doesn't grow with
+    /// the number of regions.
+    async fn wait_scatter_regions(&self, mut regions: Vec<u64>, deadline: Instant) -> Result<()> {
         let start = Instant::now();
-        while let Some(region_id) = wait_scatter_regions.pop() {
-            exec_with_retry!(
-                "scatter",
-                self.client.is_scatter_region_finished(region_id)?,
-                SCATTER_WAIT_MAX_RETRY_TIMES,
-                SCATTER_WAIT_INTERVAL_MILLIS,
-                SCATTER_MAX_WAIT_INTERVAL_MILLIS
-            );
+        let retry = &self.cfg.retry;
+        let max_retry_times = retry.scatter_wait_max_retry_times;
+        for i in 0..max_retry_times {
+            if self.progress.is_aborted() {
+                return Err(Error::ImportJobAborted(self.engine.uuid()));
+            }
+            let num_regions = regions.len();
+            // Errors have been logged, just check again in the next round.
+            regions = stream::iter(regions)
+                .map(|id| async move { (id, self.client.is_scatter_region_finished(id).await) })
+                .buffer_unordered(MAX_CONCURRENT_SCATTER_CHECKS)
+                .filter_map(|(id, res)| {
+                    future::ready(if let Ok(true) = res { None } else { Some(id) })
+                })
+                .collect()
+                .await;
+            IMPORT_SCATTER_REGION_COUNTER
+                .with_label_values(&["finished"])
+                .inc_by((num_regions - regions.len()) as i64);
+
+            let now = Instant::now();
+            if regions.is_empty() || now >= deadline || i == max_retry_times - 1 {
+                break;
+            }
+            // Exponential back-off with max wait duration
+            Delay::new(retry.scatter_wait_backoff(i + 1).min(deadline - now)).await;
         }
-        info!("scatter all regions finished"; "tag" => %self.tag, "takes" => ?start.elapsed());
 
-        Ok(num_prepares)
+        if regions.is_empty() {
+            info!("scatter all regions finished"; "tag" => %self.tag, "takes" => ?start.elapsed());
+        } else {
+            IMPORT_SCATTER_REGION_COUNTER
+                .with_label_values(&["timeout"])
+                .inc_by(regions.len() as i64);
+            warn!("wait for scatter timeout"; "tag" => %self.tag, "unfinished_regions" => %regions.len(), "takes" => ?start.elapsed());
+        }
+        Ok(())
     }
 
-    async fn run_prepare_range_job(
+    async fn run_prepare_region_job(
         &self,
-        range: RangeInfo,
+        split_keys: &[Vec<u8>],
         wait_scatter_regions: &mut Vec<u64>,
-    ) -> Result<bool> {
+    ) -> Result<usize> {
         let id = self.counter.fetch_add(1, Ordering::SeqCst);
-        let tag = format!("[PrepareRangeJob {}:{}]", self.engine.uuid(), id);
-        let job = PrepareRangeJob::new(tag, range, Arc::clone(&self.client));
+        let tag = format!("[PrepareRegionJob {}:{}]", self.engine.uuid(), id);
+        let job = PrepareRegionJob::new(tag, split_keys, Arc::clone(&self.client), &self.cfg.retry);
         job.run(wait_scatter_regions).await
     }
 }
 
-/// PrepareRangeJob is responsible for helping to split and scatter regions.
-/// according to range of data we are going to import
-struct PrepareRangeJob<Client> {
+/// PrepareRegionJob is responsible for helping to split and scatter regions.
+///
+/// It splits the region containing the first split key at all split keys
+/// inside that region in one request, and then scatters the new regions.
+struct PrepareRegionJob<'a, Client> {
     tag: String,
-    range: RangeInfo,
+    split_keys: &'a [Vec<u8>],
     client: Arc<Client>,
+    retry: &'a RetryConfig,
 }
 
-impl<Client: ImportClient> PrepareRangeJob<Client> {
-    fn new(tag: String, range: RangeInfo, client: Arc<Client>) -> PrepareRangeJob<Client> {
-        PrepareRangeJob { tag, range, client }
+impl<'a, Client: ImportClient> PrepareRegionJob<'a, Client> {
+    fn new(
+        tag: String,
+        split_keys: &'a [Vec<u8>],
+        client: Arc<Client>,
+        retry: &'a RetryConfig,
+    ) -> PrepareRegionJob<'a, Client> {
+        PrepareRegionJob {
+            tag,
+            split_keys,
+            client,
+            retry,
+        }
     }
 
-    async fn run(&self, wait_scatter_regions: &mut Vec<u64>) -> Result<bool> {
+    /// Returns the number of split keys inside the prepared region.
+    async fn run(&self, wait_scatter_regions: &mut Vec<u64>) -> Result<usize> {
         let start = Instant::now();
-        info!("prepare range"; "tag" => %self.tag, "range" => ?self.range);
+        let first_key = &self.split_keys[0];
+        info!("prepare region"; "tag" => %self.tag, "at" => ::log_wrappers::Value::key(first_key));
 
-        for i in 0..MAX_RETRY_TIMES {
+        for i in 0..self.retry.prepare_max_retry_times {
             if i != 0 {
-                Delay::new(Duration::from_secs(RETRY_INTERVAL_SECS)).await;
+                Delay::new(self.retry.prepare_backoff(i)).await;
             }
 
-            let mut region = match self.client.get_region(self.range.get_start()).await {
+            let mut region = match self.client.get_region(first_key).await {
                 Ok(region) => region,
                 Err(e) => {
                     warn!("get_region failed"; "tag" => %self.tag, "err" => %e);
@@ -185,11 +271,11 @@ impl<Client: ImportClient> PrepareRangeJob<Client> {
                 }
             };
 
-            for _ in 0..MAX_RETRY_TIMES {
-                match self.prepare(region, wait_scatter_regions).await {
-                    Ok(v) => {
-                        info!("prepare range completed"; "tag" => %self.tag, "range" => ?self.range, "takes" => ?start.elapsed());
-                        return Ok(v);
+            for _ in 0..self.retry.prepare_max_retry_times {
+                match self.prepare(region.clone(), wait_scatter_regions).await {
+                    Ok(n) => {
+                        info!("prepare region completed"; "tag" => %self.tag, "region" => %region.get_id(), "split_keys" => %n, "takes" => ?start.elapsed());
+                        return Ok(self.keys_in_region(&region).len());
                     }
                     Err(Error::UpdateRegion(new_region)) => {
                         region = new_region;
@@ -200,7 +286,7 @@ impl<Client: ImportClient> PrepareRangeJob<Client> {
             }
         }
 
-        error!("prepare range failed (too many tries)"; "tag" => %self.tag);
+        error!("prepare region failed (too many tries)"; "tag" => %self.tag);
         Err(Error::PrepareRangeJobFailed(self.tag.clone()))
     }
 
@@ -208,28 +294,30 @@ impl<Client: ImportClient> PrepareRangeJob<Client> {
         &self,
         mut region: RegionInfo,
         wait_scatter_regions: &mut Vec<u64>,
-    ) -> Result<bool> {
-        if !self.need_split(&region) {
-            return Ok(false);
+    ) -> Result<usize> {
+        let split_keys = self.need_split_keys(&region);
+        if split_keys.is_empty() {
+            return Ok(0);
         }
-        match self.split_region(&region).await {
-            Ok(new_region) => {
+        match self.split_region(&region, split_keys).await {
+            Ok(new_regions) => {
                 // We need to wait for a few milliseconds, because PD may have
                 // not received any heartbeat from the new split region, such
                 // that PD cannot create scatter operator for the new split
                 // region because it doesn't have the meta data of the new split
                 // region.
-                exec_with_retry!(
-                    "split",
-                    self.client.has_region_id(new_region.region.id).await?,
-                    SPLIT_WAIT_MAX_RETRY_TIMES,
-                    SPLIT_WAIT_INTERVAL_MILLIS,
-                    SPLIT_MAX_WAIT_INTERVAL_MILLIS
-                );
-                self.scatter_region(&new_region)?;
-                wait_scatter_regions.push(new_region.region.id);
+                for new_region in &new_regions {
+                    exec_with_retry!(
+                        "split",
+                        self.client.has_region_id(new_region.region.id).await?,
+                        self.retry.split_wait_max_retry_times,
+                        |i| self.retry.split_wait_backoff(i)
+                    );
+                }
+                self.scatter_regions(&new_regions)?;
+                wait_scatter_regions.extend(new_regions.iter().map(|r| r.get_id()));
 
-                Ok(true)
+                Ok(split_keys.len())
             }
             Err(Error::NotLeader(new_leader)) => {
                 region.leader = new_leader;
@@ -238,7 +326,7 @@ impl<Client: ImportClient> PrepareRangeJob<Client> {
             Err(Error::EpochNotMatch(current_regions)) => {
                 let current_region = current_regions
                     .iter()
-                    .find(|&r| self.need_split(r))
+                    .find(|&r| inside_region(&self.split_keys[0], r))
                     .cloned();
                 match current_region {
                     Some(current_region) => {
@@ -260,21 +348,33 @@ impl<Client: ImportClient> PrepareRangeJob<Client> {
         }
     }
 
-    /// Judges if we need to split the region.
-    fn need_split(&self, region: &Region) -> bool {
-        let split_key = self.range.get_end();
-        if split_key.is_empty() {
-            return false;
-        }
-        if split_key <= region.get_start_key() {
-            return false;
-        }
-        before_end(split_key, region.get_end_key())
+    /// Returns the split keys inside the region.
+    fn keys_in_region(&self, region: &Region) -> &'a [Vec<u8>] {
+        let n = self
+            .split_keys
+            .iter()
+            .take_while(|k| before_end(k, region.get_end_key()))
+            .count();
+        &self.split_keys[..n]
     }
 
-    async fn split_region(&self, region: &RegionInfo) -> Result<RegionInfo> {
-        let split_key = self.range.get_end();
-        let res = match self.client.split_region(region, split_key).await {
+    /// Returns the split keys which we need to split the region at.
+    fn need_split_keys(&self, region: &Region) -> &'a [Vec<u8>] {
+        let keys = self.keys_in_region(region);
+        // The region has been split at its start key.
+        let n = keys
+            .iter()
+            .take_while(|k| k.as_slice() <= region.get_start_key())
+            .count();
+        &keys[n..]
+    }
+
+    async fn split_region(
+        &self,
+        region: &RegionInfo,
+        split_keys: &[Vec<u8>],
+    ) -> Result<Vec<RegionInfo>> {
+        let res = match self.client.split_regions(region, split_keys).await {
             Ok(mut resp) => {
                 if !resp.has_region_error() {
                     Ok(resp)
@@ -290,32 +390,39 @@ impl<Client: ImportClient> PrepareRangeJob<Client> {
 
         match res {
             Ok(mut resp) => {
-                info!("split completed"; "tag" => %self.tag, "region" => ?ReadableDebug(region), "at" => ::log_wrappers::Value::key(split_key));
+                info!("split completed"; "tag" => %self.tag, "region" => ?ReadableDebug(region), "split_keys" => %split_keys.len());
                 // Just assume that the leader will be at the same store.
-                let left = resp.take_left();
-                let leader = match region.leader {
-                    Some(ref p) => find_region_peer(&left, p.get_store_id()),
-                    None => None,
-                };
-                Ok(RegionInfo::new(left, leader))
+                let store_id = region.leader.as_ref().map(|p| p.get_store_id());
+                // The origin region is kept as one of the split regions, only
+                // the new regions need to be scattered.
+                let new_regions = resp
+                    .take_regions()
+                    .into_iter()
+                    .filter(|r| r.get_id() != region.get_id())
+                    .map(|r| {
+                        let leader = store_id.and_then(|id| find_region_peer(&r, id));
+                        RegionInfo::new(r, leader)
+                    })
+                    .collect();
+                Ok(new_regions)
             }
             Err(e) => {
                 warn!(
-                    "split failed"; "tag" => %self.tag, "region" => ?ReadableDebug(region), "at" => ::log_wrappers::Value::key(split_key), "err" => %e
+                    "split failed"; "tag" => %self.tag, "region" => ?ReadableDebug(region), "at" => ::log_wrappers::Value::key(&split_keys[0]), "split_keys" => %split_keys.len(), "err" => %e
                 );
                 Err(e)
             }
         }
     }
 
-    fn scatter_region(&self, region: &RegionInfo) -> Result<()> {
-        match self.client.scatter_region(region) {
+    fn scatter_regions(&self, regions: &[RegionInfo]) -> Result<()> {
+        match self.client.scatter_regions(regions) {
             Ok(_) => {
-                info!("scatter completed"; "tag" => %self.tag, "region" => %region.get_id());
+                info!("scatter completed"; "tag" => %self.tag, "regions" => %regions.len());
                 Ok(())
             }
             Err(e) => {
-                warn!("scatter failed"; "tag" => %self.tag, "region" => %region.get_id(), "err" => %e);
+                warn!("scatter failed"; "tag" => %self.tag, "regions" => %regions.len(), "err" => %e);
                 Err(e)
             }
         }
@@ -333,6 +440,7 @@ mod tests {
     use uuid::Uuid;
 
     use tikv::config::DbConfig;
+    use tikv_util::config::ReadableDuration;
     use txn_types::Key;
 
     fn new_encoded_key(k: &[u8]) -> Vec<u8> {
@@ -348,8 +456,7 @@ mod tests {
         let dir = TempDir::new("test_import_prepare_job").unwrap();
         let uuid = Uuid::new_v4();
         let db_cfg = DbConfig::default();
-        let security_mgr = Arc::default();
-        let engine = Arc::new(Engine::new(dir.path(), uuid, db_cfg, security_mgr).unwrap());
+        let engine = Arc::new(Engine::new(dir.path(), uuid, db_cfg, None, false).unwrap());
 
         // Generate entries to prepare.
         let (n, m) = (4, 4);
@@ -437,6 +544,110 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_prepare_job_timeout() {
+        let dir = TempDir::new("test_import_prepare_job_timeout").unwrap();
+        let uuid = Uuid::new_v4();
+        let db_cfg = DbConfig::default();
+        let engine = Arc::new(Engine::new(dir.path(), uuid, db_cfg, None, false).unwrap());
+
+        let index_size = 10;
+        for i in 0..16 {
+            let v = &[i + 1];
+            let k = new_encoded_key(v);
+            engine.put(&k, v).unwrap();
+            assert_eq!(k.len() + v.len(), index_size);
+            engine.flush(true).unwrap();
+        }
+
+        let mut cfg = Config::default();
+        cfg.num_import_jobs = 4;
+        cfg.region_split_size.0 = index_size as u64 * 3;
+        // Prepare exceeds the deadline at once.
+        cfg.max_prepare_duration = ReadableDuration::secs(0);
+
+        // Ranges are returned as usual.
+        let ranges = vec![
+            (vec![], vec![4]),
+            (vec![4], vec![8]),
+            (vec![8], vec![12]),
+            (vec![12], vec![]),
+        ];
+
+        let mut client = MockClient::new();
+        client.add_region_range(b"", b"");
+        // No region is split.
+        let region_ranges = vec![(vec![], vec![], false)];
+        block_on(run_and_check_prepare_job(
+            cfg,
+            client,
+            engine,
+            &ranges,
+            &region_ranges,
+        ));
+    }
+
+    #[test]
+    fn test_prepare_job_batch_split() {
+        let dir = TempDir::new("test_import_prepare_job_batch_split").unwrap();
+        let uuid = Uuid::new_v4();
+        let db_cfg = DbConfig::default();
+        let engine = Arc::new(Engine::new(dir.path(), uuid, db_cfg, None, false).unwrap());
+
+        let index_size = 10;
+        for i in 0..16 {
+            let v = &[i + 1];
+            let k = new_encoded_key(v);
+            engine.put(&k, v).unwrap();
+            assert_eq!(k.len() + v.len(), index_size);
+            engine.flush(true).unwrap();
+        }
+
+        let mut cfg = Config::default();
+        cfg.num_import_jobs = 4;
+        cfg.region_split_size.0 = index_size as u64 * 3;
+
+        let ranges = vec![
+            (vec![], vec![4]),
+            (vec![4], vec![8]),
+            (vec![8], vec![12]),
+            (vec![12], vec![]),
+        ];
+        let region_ranges = vec![
+            (vec![], vec![3], true),
+            (vec![3], vec![6], true),
+            (vec![6], vec![9], true),
+            (vec![9], vec![12], true),
+            (vec![12], vec![15], true),
+            (vec![15], vec![], false),
+        ];
+
+        // All split keys in one region are split in one request.
+        let mut client = MockClient::new();
+        client.add_region_range(b"", b"");
+        block_on(run_and_check_prepare_job(
+            cfg.clone(),
+            client.clone(),
+            Arc::clone(&engine),
+            &ranges,
+            &region_ranges,
+        ));
+        assert_eq!(client.get_split_requests(), vec![5]);
+
+        // The split keys are sent in batches of at most 2 keys.
+        cfg.max_split_keys_per_request = 2;
+        let mut client = MockClient::new();
+        client.add_region_range(b"", b"");
+        block_on(run_and_check_prepare_job(
+            cfg,
+            client.clone(),
+            engine,
+            &ranges,
+            &region_ranges,
+        ));
+        assert_eq!(client.get_split_requests(), vec![2, 2, 1]);
+    }
+
     async fn run_and_check_prepare_job(
         cfg: Config,
         client: MockClient,
@@ -444,7 +655,8 @@ mod tests {
         expected_ranges: &[(Vec<u8>, Vec<u8>)],
         expected_region_ranges: &[(Vec<u8>, Vec<u8>, bool)],
     ) {
-        let job = PrepareJob::new(cfg, client.clone(), Arc::clone(&engine));
+        let progress = Arc::new(ImportProgress::new(engine.uuid()));
+        let job = PrepareJob::new(cfg, client.clone(), Arc::clone(&engine), progress);
 
         let ranges = job.run().await.unwrap();
         assert_eq!(ranges.len(), expected_ranges.len());
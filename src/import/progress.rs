@@ -0,0 +1,171 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use uuid::Uuid;
+
+use super::metrics::*;
+use super::Result;
+
+/// Phase of an import job.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImportPhase {
+    /// Splitting and scattering regions.
+    Prepare,
+    /// Generating, uploading and ingesting SST files.
+    Import,
+    /// The job has finished, successfully or not.
+    Finished,
+}
+
+impl Default for ImportPhase {
+    fn default() -> ImportPhase {
+        ImportPhase::Prepare
+    }
+}
+
+/// A snapshot of the progress of an import job.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportStatus {
+    pub phase: ImportPhase,
+    /// Estimated number of ranges to import, known after the prepare phase.
+    pub ranges_total: usize,
+    /// Number of ranges which have been imported.
+    pub ranges_finished: usize,
+    /// Number of ranges which failed and are waiting for retry.
+    pub ranges_retrying: usize,
+    /// Bytes uploaded to all peers.
+    pub bytes_uploaded: u64,
+    /// The error of a failed job.
+    pub error: Option<String>,
+}
+
+/// ImportProgress is shared by an import job and its sub jobs to report the
+/// progress of the job. The progress is exported as metrics as well, until
+/// the job finishes.
+///
+/// It also tells the sub jobs to stop once the job is aborted.
+pub struct ImportProgress {
+    uuid: String,
+    status: Mutex<ImportStatus>,
+    aborted: AtomicBool,
+}
+
+impl ImportProgress {
+    pub fn new(uuid: Uuid) -> ImportProgress {
+        let progress = ImportProgress {
+            uuid: uuid.to_string(),
+            status: Mutex::new(ImportStatus::default()),
+            aborted: AtomicBool::new(false),
+        };
+        progress.update(|_| {});
+        progress
+    }
+
+    pub fn status(&self) -> ImportStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn set_phase(&self, phase: ImportPhase) {
+        self.update(|s| s.phase = phase);
+    }
+
+    pub fn set_ranges_total(&self, n: usize) {
+        self.update(|s| s.ranges_total = n);
+    }
+
+    pub fn finish_range(&self) {
+        self.update(|s| s.ranges_finished += 1);
+    }
+
+    pub fn retry_range(&self) {
+        self.update(|s| s.ranges_retrying += 1);
+    }
+
+    /// Resets the retrying ranges when they are dispatched again.
+    pub fn reset_retrying_ranges(&self) {
+        self.update(|s| s.ranges_retrying = 0);
+    }
+
+    pub fn add_uploaded_bytes(&self, n: u64) {
+        self.update(|s| s.bytes_uploaded += n);
+    }
+
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+
+    pub fn finish(&self, res: &Result<()>) {
+        self.update(|s| {
+            s.phase = ImportPhase::Finished;
+            s.error = res.as_ref().err().map(|e| e.to_string());
+        });
+    }
+
+    fn update<F: FnOnce(&mut ImportStatus)>(&self, f: F) {
+        let mut status = self.status.lock().unwrap();
+        f(&mut status);
+        for &(ty, v) in &[
+            ("ranges_total", status.ranges_total as f64),
+            ("ranges_finished", status.ranges_finished as f64),
+            ("ranges_retrying", status.ranges_retrying as f64),
+            ("bytes_uploaded", status.bytes_uploaded as f64),
+        ] {
+            let labels = [self.uuid.as_str(), ty];
+            if status.phase == ImportPhase::Finished {
+                // The status of a finished job is kept by the importer, so
+                // the metrics of each job don't pile up.
+                let _ = IMPORT_JOB_PROGRESS.remove_label_values(&labels);
+            } else {
+                IMPORT_JOB_PROGRESS.with_label_values(&labels).set(v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::Error;
+
+    #[test]
+    fn test_import_progress() {
+        let uuid = Uuid::new_v4();
+        let progress = ImportProgress::new(uuid);
+        assert_eq!(progress.status(), ImportStatus::default());
+
+        progress.set_ranges_total(3);
+        progress.set_phase(ImportPhase::Import);
+        progress.finish_range();
+        progress.retry_range();
+        progress.retry_range();
+        progress.add_uploaded_bytes(10);
+        let status = progress.status();
+        assert_eq!(status.phase, ImportPhase::Import);
+        assert_eq!(status.ranges_total, 3);
+        assert_eq!(status.ranges_finished, 1);
+        assert_eq!(status.ranges_retrying, 2);
+        assert_eq!(status.bytes_uploaded, 10);
+
+        progress.reset_retrying_ranges();
+        assert_eq!(progress.status().ranges_retrying, 0);
+
+        assert!(!progress.is_aborted());
+        progress.abort();
+        assert!(progress.is_aborted());
+
+        progress.finish(&Err(Error::ImportJobFailed("failed".to_owned())));
+        let status = progress.status();
+        assert_eq!(status.phase, ImportPhase::Finished);
+        assert_eq!(status.error, Some("failed".to_owned()));
+        // Metrics of the job have been removed.
+        assert!(IMPORT_JOB_PROGRESS
+            .remove_label_values(&[&uuid.to_string(), "ranges_total"])
+            .is_err());
+    }
+}
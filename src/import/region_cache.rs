@@ -0,0 +1,176 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use pd_client::RegionInfo;
+
+use collections::HashMap;
+
+use super::common::*;
+
+/// RegionCache caches regions loaded from PD, so that looking up the region
+/// of a key doesn't need to ask PD every time.
+///
+/// Regions in the cache may be stale, a region must be invalidated once it
+/// is found stale, e.g. on NotLeader or EpochNotMatch errors.
+///
+/// At most `capacity` regions are cached. Ranges are imported in the order
+/// of keys, so regions with the smallest start keys are evicted first.
+pub struct RegionCache {
+    capacity: usize,
+    // Regions indexed by their start keys.
+    regions: BTreeMap<Vec<u8>, RegionInfo>,
+    // Start keys of regions indexed by region ids.
+    start_keys: HashMap<u64, Vec<u8>>,
+}
+
+impl RegionCache {
+    pub fn new(capacity: usize) -> RegionCache {
+        assert!(capacity > 0);
+        RegionCache {
+            capacity,
+            regions: BTreeMap::new(),
+            start_keys: HashMap::default(),
+        }
+    }
+
+    /// Returns the cached region containing `key`.
+    pub fn get(&self, key: &[u8]) -> Option<RegionInfo> {
+        let (_, region) = self
+            .regions
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()?;
+        if inside_region(key, region) {
+            Some(region.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Inserts the region, cached regions overlapped with it are removed.
+    pub fn insert(&mut self, region: RegionInfo) {
+        self.invalidate(region.get_id());
+
+        let start = region.get_start_key();
+        let end = region.get_end_key();
+        let end_bound = if end == RANGE_MAX {
+            Bound::Unbounded
+        } else {
+            Bound::Excluded(end)
+        };
+        // Cached regions don't overlap with each other, so the end keys are
+        // in the same order as the start keys.
+        let overlapped: Vec<Vec<u8>> = self
+            .regions
+            .range::<[u8], _>((Bound::Unbounded, end_bound))
+            .rev()
+            .take_while(|(_, r)| before_end(start, r.get_end_key()))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in overlapped {
+            if let Some(r) = self.regions.remove(&key) {
+                self.start_keys.remove(&r.get_id());
+            }
+        }
+
+        if self.regions.len() >= self.capacity {
+            let first = self.regions.keys().next().cloned().unwrap();
+            if let Some(r) = self.regions.remove(&first) {
+                self.start_keys.remove(&r.get_id());
+            }
+        }
+
+        self.start_keys.insert(region.get_id(), start.to_owned());
+        self.regions.insert(start.to_owned(), region);
+    }
+
+    /// Removes the region from the cache.
+    pub fn invalidate(&mut self, region_id: u64) {
+        if let Some(key) = self.start_keys.remove(&region_id) {
+            self.regions.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use kvproto::metapb::Region;
+
+    fn new_region_info(id: u64, start: &[u8], end: &[u8]) -> RegionInfo {
+        let mut region = Region::default();
+        region.set_id(id);
+        region.set_start_key(start.to_vec());
+        region.set_end_key(end.to_vec());
+        RegionInfo::new(region, None)
+    }
+
+    fn check_region(cache: &RegionCache, key: &[u8], id: Option<u64>) {
+        assert_eq!(cache.get(key).map(|r| r.get_id()), id, "key {:?}", key);
+    }
+
+    #[test]
+    fn test_region_cache() {
+        let mut cache = RegionCache::new(16);
+        check_region(&cache, b"a", None);
+
+        cache.insert(new_region_info(1, b"", b"b"));
+        cache.insert(new_region_info(2, b"c", b"e"));
+        cache.insert(new_region_info(3, b"e", b""));
+        check_region(&cache, b"", Some(1));
+        check_region(&cache, b"a", Some(1));
+        check_region(&cache, b"b", None);
+        check_region(&cache, b"c", Some(2));
+        check_region(&cache, b"d", Some(2));
+        check_region(&cache, b"e", Some(3));
+        check_region(&cache, b"zzz", Some(3));
+
+        // Region 2 is split into [c, d) and [d, e).
+        cache.insert(new_region_info(4, b"c", b"d"));
+        cache.insert(new_region_info(2, b"d", b"e"));
+        check_region(&cache, b"c", Some(4));
+        check_region(&cache, b"d", Some(2));
+        check_region(&cache, b"e", Some(3));
+
+        // Regions [c, d), [d, e) and [e, ) are merged into [b, ).
+        cache.insert(new_region_info(5, b"b", b""));
+        check_region(&cache, b"a", Some(1));
+        for key in &[b"b", b"c", b"d", b"e", b"f"] {
+            check_region(&cache, *key, Some(5));
+        }
+        assert_eq!(cache.regions.len(), 2);
+        assert_eq!(cache.start_keys.len(), 2);
+
+        cache.invalidate(5);
+        check_region(&cache, b"c", None);
+        check_region(&cache, b"a", Some(1));
+        cache.invalidate(5);
+        cache.invalidate(1);
+        check_region(&cache, b"a", None);
+        assert!(cache.regions.is_empty());
+        assert!(cache.start_keys.is_empty());
+    }
+
+    #[test]
+    fn test_region_cache_capacity() {
+        let mut cache = RegionCache::new(2);
+        cache.insert(new_region_info(1, b"", b"b"));
+        cache.insert(new_region_info(2, b"b", b"c"));
+        check_region(&cache, b"a", Some(1));
+
+        // The region with the smallest start key is evicted.
+        cache.insert(new_region_info(3, b"c", b""));
+        check_region(&cache, b"a", None);
+        check_region(&cache, b"b", Some(2));
+        check_region(&cache, b"c", Some(3));
+        assert_eq!(cache.regions.len(), 2);
+        assert_eq!(cache.start_keys.len(), 2);
+
+        // Replacing a cached region doesn't evict others.
+        cache.insert(new_region_info(4, b"c", b""));
+        check_region(&cache, b"b", Some(2));
+        check_region(&cache, b"c", Some(4));
+    }
+}
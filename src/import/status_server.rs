@@ -1,52 +1,317 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use engine_rocks::RocksEngine;
-use raftstore::store::{transport::CasualRouter, CasualMessage};
+use futures::channel::oneshot;
+use futures::future;
+use futures::prelude::*;
+use futures::stream;
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use hyper::server::Builder;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use prometheus::{Encoder, TextEncoder};
 use security::SecurityConfig;
-use tikv::config::ConfigController;
-use tikv::server::status_server::StatusServer as TiKVStatusServer;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::{Builder as RuntimeBuilder, Runtime};
+use tokio_openssl::SslStream;
+use uuid::Uuid;
 
-#[derive(Clone)]
-struct MockRouter;
+use super::{Error, KVImporter};
 
-impl CasualRouter<RocksEngine> for MockRouter {
-    fn send(&self, region_id: u64, _: CasualMessage<RocksEngine>) -> raftstore::Result<()> {
-        Err(raftstore::Error::RegionNotFound(region_id))
-    }
-}
+const METRICS_PATH: &str = "/metrics";
+const STATUS_PATH: &str = "/status";
+/// Engine stats are served at `/engines/<uuid>/stats`.
+const ENGINES_PATH: &str = "/engines/";
+const STATS_SUFFIX: &str = "/stats";
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// StatusServer is an HTTP server that provides the metrics of the importer
+/// and the stats of engines.
 pub struct StatusServer {
-    inner_server: TiKVStatusServer<RocksEngine, MockRouter>,
+    runtime: Runtime,
     addr: String,
+    security_cfg: SecurityConfig,
+    importer: Arc<KVImporter>,
+    tx: Option<oneshot::Sender<()>>,
 }
 
 impl StatusServer {
-    pub fn new(addr: &str, security_cfg: SecurityConfig) -> StatusServer {
+    pub fn new(
+        addr: &str,
+        security_cfg: SecurityConfig,
+        importer: Arc<KVImporter>,
+    ) -> StatusServer {
+        let runtime = RuntimeBuilder::new()
+            .threaded_scheduler()
+            .core_threads(1)
+            .thread_name("status-server")
+            .enable_all()
+            .build()
+            .expect("failed to create status server");
         StatusServer {
-            inner_server: TiKVStatusServer::new(
-                1,
-                None,
-                ConfigController::default(),
-                Arc::new(security_cfg),
-                MockRouter,
-            )
-            .expect("failed to create status server"),
+            runtime,
             addr: addr.to_owned(),
+            security_cfg,
+            importer,
+            tx: None,
         }
     }
 
     pub fn start(&mut self) {
-        if let Err(e) = self
-            .inner_server
-            .start(self.addr.clone(), self.addr.clone())
-        {
+        if let Err(e) = self.start_serve() {
             warn!("fail to setup status server: {:?}", e)
         }
     }
 
-    pub fn shutdown(self) {
-        self.inner_server.stop()
+    fn start_serve(&mut self) -> Result<(), Box<dyn StdError>> {
+        let addr = SocketAddr::from_str(&self.addr)?;
+        let incoming = self.runtime.enter(|| AddrIncoming::bind(&addr))?;
+        if self.security_cfg.cert_path.is_empty() {
+            self.serve(Server::builder(incoming));
+        } else {
+            let acceptor = Arc::new(new_tls_acceptor(&self.security_cfg)?);
+            self.serve(Server::builder(tls_incoming(acceptor, incoming)));
+        }
+        Ok(())
+    }
+
+    fn serve<I>(&mut self, builder: Builder<I>)
+    where
+        I: Accept + Send + 'static,
+        I::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        I::Error: Into<Box<dyn StdError + Send + Sync>>,
+    {
+        let importer = Arc::clone(&self.importer);
+        let make_service = make_service_fn(move |_| {
+            let importer = Arc::clone(&importer);
+            future::ok::<_, Infallible>(service_fn(move |req| {
+                handle_request(Arc::clone(&importer), req).map(Ok::<_, Infallible>)
+            }))
+        });
+        let (tx, rx) = oneshot::channel();
+        let server = builder
+            .serve(make_service)
+            .with_graceful_shutdown(rx.map(drop))
+            .map(|res| {
+                if let Err(e) = res {
+                    error!("status server error: {:?}", e);
+                }
+            });
+        self.runtime.spawn(server);
+        self.tx = Some(tx);
+    }
+
+    pub fn shutdown(mut self) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(());
+        }
+        self.runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    }
+}
+
+/// Creates a TLS acceptor which only accepts clients with certificates
+/// signed by the CA and, if specified, with allowed common names.
+fn new_tls_acceptor(cfg: &SecurityConfig) -> Result<SslAcceptor, Box<dyn StdError>> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_ca_file(&cfg.ca_path)?;
+    builder.set_certificate_chain_file(&cfg.cert_path)?;
+    builder.set_private_key_file(&cfg.key_path, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    let allowed_cn = cfg.cert_allowed_cn.clone();
+    builder.set_verify_callback(
+        SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        move |preverified, ctx| {
+            // Only the common name of the client certificate is checked.
+            if !preverified || allowed_cn.is_empty() || ctx.error_depth() != 0 {
+                return preverified;
+            }
+            ctx.current_cert().map_or(false, |cert| {
+                cert.subject_name()
+                    .entries_by_nid(Nid::COMMONNAME)
+                    .any(|cn| {
+                        cn.data()
+                            .as_utf8()
+                            .map_or(false, |cn| allowed_cn.contains(&*cn))
+                    })
+            })
+        },
+    );
+    Ok(builder.build())
+}
+
+/// Wraps accepted connections with TLS. Connections failed to handshake are
+/// dropped.
+fn tls_incoming(
+    acceptor: Arc<SslAcceptor>,
+    incoming: AddrIncoming,
+) -> impl Accept<Conn = SslStream<AddrStream>, Error = Infallible> {
+    let conns = stream::unfold(incoming, |mut incoming| async move {
+        let conn = future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)).await;
+        conn.map(|conn| (conn, incoming))
+    });
+    let tls_conns = conns.filter_map(move |conn| {
+        let acceptor = Arc::clone(&acceptor);
+        async move {
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("status server failed to accept connection"; "err" => %e);
+                    return None;
+                }
+            };
+            match tokio_openssl::accept(&acceptor, conn).await {
+                Ok(conn) => Some(Ok::<_, Infallible>(conn)),
+                Err(e) => {
+                    warn!("status server failed to handshake"; "err" => ?e);
+                    None
+                }
+            }
+        }
+    });
+    accept::from_stream(Box::pin(tls_conns))
+}
+
+async fn handle_request(importer: Arc<KVImporter>, req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return new_response(StatusCode::METHOD_NOT_ALLOWED, "");
+    }
+    let path = req.uri().path();
+    if path == METRICS_PATH {
+        return metrics();
+    }
+    if path == STATUS_PATH {
+        return new_response(StatusCode::OK, "");
+    }
+    if let Some(uuid) = path
+        .strip_prefix(ENGINES_PATH)
+        .and_then(|p| p.strip_suffix(STATS_SUFFIX))
+    {
+        return engine_stats(importer, uuid).await;
+    }
+    new_response(StatusCode::NOT_FOUND, "")
+}
+
+fn metrics() -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buf) {
+        return new_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+    Response::builder()
+        .header(header::CONTENT_TYPE, encoder.format_type())
+        .body(buf.into())
+        .unwrap()
+}
+
+/// Responds the stats of the engine as JSON.
+async fn engine_stats(importer: Arc<KVImporter>, uuid: &str) -> Response<Body> {
+    let uuid = match Uuid::parse_str(uuid) {
+        Ok(uuid) => uuid,
+        Err(e) => return new_response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    // Engines are scanned on the blocking threads, so that other requests
+    // will not be blocked.
+    let res = match tokio::task::spawn_blocking(move || importer.engine_stats(uuid)).await {
+        Ok(res) => res,
+        Err(e) => return new_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    match res {
+        Ok(stats) => match serde_json::to_vec(&stats) {
+            Ok(body) => Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.into())
+                .unwrap(),
+            Err(e) => new_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        },
+        Err(e @ Error::EngineNotFound(_)) => new_response(StatusCode::NOT_FOUND, e.to_string()),
+        Err(e @ Error::EngineInUse(_)) => new_response(StatusCode::CONFLICT, e.to_string()),
+        Err(e) => new_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+fn new_response<T: Into<Body>>(status: StatusCode, body: T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use kvproto::import_kvpb::KvPair;
+    use pd_client::Config as PdConfig;
+    use tempdir::TempDir;
+    use tikv::config::DbConfig;
+
+    use crate::import::Config;
+
+    async fn get(importer: &Arc<KVImporter>, uri: &str) -> (StatusCode, Vec<u8>) {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let resp = handle_request(Arc::clone(importer), req).await;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    #[test]
+    fn test_engine_stats() {
+        let temp_dir = TempDir::new("test_status_server_engine_stats").unwrap();
+
+        let mut cfg = Config::default();
+        cfg.import_dir = temp_dir.path().to_str().unwrap().to_owned();
+        let importer = KVImporter::new(
+            cfg,
+            DbConfig::default(),
+            PdConfig::default(),
+            Arc::default(),
+            None,
+        )
+        .unwrap();
+        let importer = Arc::new(importer);
+
+        let uuid = Uuid::new_v4();
+        importer.open_engine(uuid).unwrap();
+        let engine = importer.bind_engine(uuid).unwrap();
+        let mut pair = KvPair::default();
+        pair.set_key(b"k".to_vec());
+        pair.set_value(b"v".to_vec());
+        engine.write_v3(1, &[pair], "test").unwrap();
+        drop(engine);
+
+        let mut runtime = RuntimeBuilder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (status, body) = get(&importer, &format!("/engines/{}/stats", uuid)).await;
+            assert_eq!(status, StatusCode::OK);
+            let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(stats["kvs"], 1);
+            assert_eq!(stats["smallest-key"], hex::encode(b"k"));
+
+            let unknown = format!("/engines/{}/stats", Uuid::new_v4());
+            assert_eq!(get(&importer, &unknown).await.0, StatusCode::NOT_FOUND);
+            let invalid = "/engines/invalid/stats";
+            assert_eq!(get(&importer, invalid).await.0, StatusCode::BAD_REQUEST);
+            assert_eq!(
+                get(&importer, "/engines/stats").await.0,
+                StatusCode::NOT_FOUND
+            );
+            assert_eq!(get(&importer, METRICS_PATH).await.0, StatusCode::OK);
+        });
     }
 }
@@ -81,7 +81,10 @@ impl<Client: ImportClient> SSTFileStream<Client> {
             {
                 let k = self.iter.key();
                 let v = self.iter.value();
-                w.put(k, v)?;
+                match EngineValue::decode(v)? {
+                    EngineValue::Put(value) => w.put(k, value)?,
+                    EngineValue::Delete => w.delete(k)?,
+                }
                 self.ctx.add(k.len() + v.len());
             }
             if !self.iter.next()? || self.ctx.should_stop_before(self.iter.key()) {
@@ -101,6 +104,34 @@ impl<Client: ImportClient> SSTFileStream<Client> {
     }
 }
 
+/// Generates a new SST of column family `cf_name` which contains all keys of
+/// `range` in the engine, returns None if there is no such key.
+pub fn new_sst_for_range(
+    engine: &Engine,
+    range: Range,
+    cf_name: &str,
+) -> Result<Option<LazySSTInfo>> {
+    let mut iter = RangeIterator::new(engine.new_iter(true), range, Vec::new());
+    if !iter.valid()? {
+        return Ok(None);
+    }
+
+    let mut w = engine.new_sst_writer()?;
+    loop {
+        match EngineValue::decode(iter.value())? {
+            EngineValue::Put(value) => w.put(iter.key(), value)?,
+            EngineValue::Delete => w.delete(iter.key())?,
+        }
+        if !iter.next()? {
+            break;
+        }
+    }
+
+    // SSTs of other column families are deleted on drop.
+    let infos = w.finish()?;
+    Ok(infos.into_iter().find(|info| info.cf_name == cf_name))
+}
+
 pub struct RangeIterator {
     iter: DBIterator<Arc<DB>>,
     ranges: Vec<Range>,
@@ -204,6 +235,7 @@ mod tests {
     use futures::executor::block_on;
     use tempdir::TempDir;
 
+    use engine_traits::{CF_DEFAULT, CF_WRITE};
     use tikv::config::DbConfig;
     use txn_types::{Key, TimeStamp};
 
@@ -370,13 +402,13 @@ mod tests {
         let dir = TempDir::new("test_import_sst_file_stream").unwrap();
         let uuid = Uuid::new_v4();
         let db_cfg = DbConfig::default();
-        let security_mgr = Arc::default();
-        let engine = Arc::new(Engine::new(dir.path(), uuid, db_cfg, security_mgr).unwrap());
+        let engine = Arc::new(Engine::new(dir.path(), uuid, db_cfg, None, false).unwrap());
 
         for i in 0..16 {
             let k = Key::from_raw(&[i]).append_ts(TimeStamp::zero());
             assert_eq!(k.as_encoded().len(), 17);
-            engine.put(k.as_encoded(), k.as_encoded()).unwrap();
+            let v = EngineValue::Put(k.as_encoded()).encode();
+            engine.put(k.as_encoded(), &v).unwrap();
         }
 
         let mut cfg = Config::default();
@@ -440,6 +472,43 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_new_sst_for_range() {
+        let dir = TempDir::new("test_import_new_sst_for_range").unwrap();
+        let uuid = Uuid::new_v4();
+        let db_cfg = DbConfig::default();
+        let engine = Engine::new(dir.path(), uuid, db_cfg, None, false).unwrap();
+
+        for i in 0..16 {
+            let k = Key::from_raw(&[i]).append_ts(TimeStamp::zero());
+            let v = EngineValue::Put(k.as_encoded()).encode();
+            engine.put(k.as_encoded(), &v).unwrap();
+        }
+
+        // Short values are stored in write CF only.
+        let info = new_sst_for_range(&engine, new_encoded_range(4, 8), CF_WRITE)
+            .unwrap()
+            .unwrap();
+        let (start, end) = (
+            Key::from_raw(&[4]).append_ts(TimeStamp::zero()),
+            Key::from_raw(&[7]).append_ts(TimeStamp::zero()),
+        );
+        assert_eq!(info.range.get_start(), start.as_encoded().as_slice());
+        assert_eq!(info.range.get_end(), end.as_encoded().as_slice());
+        assert!(
+            new_sst_for_range(&engine, new_encoded_range(4, 8), CF_DEFAULT)
+                .unwrap()
+                .is_none()
+        );
+
+        // No keys in the range.
+        assert!(
+            new_sst_for_range(&engine, new_encoded_range(16, 32), CF_WRITE)
+                .unwrap()
+                .is_none()
+        );
+    }
+
     fn run_and_check_stream(
         cfg: Config,
         client: Arc<MockClient>,
@@ -1,14 +1,19 @@
 // Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
 
+use std::fs;
+use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use futures::future::{self, BoxFuture, FutureExt};
+use kvproto::import_sstpb::{IngestRequest, IngestResponse, SstMeta, UploadResponse};
 use kvproto::kvrpcpb::*;
 use kvproto::metapb::*;
 
 use collections::HashMap;
+use encryption_export::{data_key_manager_from_config, DataKeyManager};
 use pd_client::RegionInfo;
+use security::SecurityConfig;
 
 use super::client::*;
 use super::common::*;
@@ -19,6 +24,10 @@ pub struct MockClient {
     counter: Arc<AtomicUsize>,
     regions: Arc<Mutex<HashMap<u64, Region>>>,
     scatter_regions: Arc<Mutex<HashMap<u64, Region>>>,
+    // The number of split keys of each split request.
+    split_requests: Arc<Mutex<Vec<usize>>>,
+    // SSTs in the order of ingestion.
+    ingested_ssts: Arc<Mutex<Vec<SstMeta>>>,
 }
 
 impl MockClient {
@@ -27,6 +36,8 @@ impl MockClient {
             counter: Arc::new(AtomicUsize::new(1)),
             regions: Arc::new(Mutex::new(HashMap::default())),
             scatter_regions: Arc::new(Mutex::new(HashMap::default())),
+            split_requests: Arc::default(),
+            ingested_ssts: Arc::default(),
         }
     }
 
@@ -51,6 +62,14 @@ impl MockClient {
         let regions = self.scatter_regions.lock().unwrap();
         regions.get(&id).map(|r| RegionInfo::new(r.clone(), None))
     }
+
+    pub fn get_split_requests(&self) -> Vec<usize> {
+        self.split_requests.lock().unwrap().clone()
+    }
+
+    pub fn get_ingested_ssts(&self) -> Vec<SstMeta> {
+        self.ingested_ssts.lock().unwrap().clone()
+    }
 }
 
 impl ImportClient for MockClient {
@@ -65,37 +84,40 @@ impl ImportClient for MockClient {
         future::ok(RegionInfo::new(found.unwrap(), None)).boxed()
     }
 
-    fn split_region(
+    fn split_regions(
         &self,
         _: &RegionInfo,
-        split_key: &[u8],
+        split_keys: &[Vec<u8>],
     ) -> BoxFuture<'_, Result<SplitRegionResponse>> {
+        self.split_requests.lock().unwrap().push(split_keys.len());
         let mut regions = self.regions.lock().unwrap();
 
         let region = regions
-            .iter()
-            .map(|(_, r)| r)
-            .find(|r| {
-                split_key >= r.get_start_key()
-                    && (split_key < r.get_end_key() || r.get_end_key().is_empty())
-            })
+            .values()
+            .find(|r| inside_region(&split_keys[0], r))
             .unwrap()
             .clone();
 
         regions.remove(&region.get_id());
 
-        let mut left = region.clone();
-        left.set_id(self.alloc_id());
-        left.set_end_key(split_key.to_vec());
-        regions.insert(left.get_id(), left.clone());
+        // Like TiKV, the right most region keeps the id of the origin region.
+        let mut resp = SplitRegionResponse::default();
+        let mut start = region.get_start_key().to_vec();
+        for split_key in split_keys {
+            let mut left = region.clone();
+            left.set_id(self.alloc_id());
+            left.set_start_key(start);
+            left.set_end_key(split_key.clone());
+            regions.insert(left.get_id(), left.clone());
+            resp.mut_regions().push(left);
+            start = split_key.clone();
+        }
 
-        let mut right = region.clone();
-        right.set_start_key(split_key.to_vec());
+        let mut right = region;
+        right.set_start_key(start);
         regions.insert(right.get_id(), right.clone());
+        resp.mut_regions().push(right);
 
-        let mut resp = SplitRegionResponse::default();
-        resp.set_left(left);
-        resp.set_right(right);
         future::ok(resp).boxed()
     }
 
@@ -105,16 +127,50 @@ impl ImportClient for MockClient {
         Ok(())
     }
 
+    fn upload_sst(&self, _: u64, _: UploadStream) -> BoxFuture<'_, Result<UploadResponse>> {
+        future::ok(UploadResponse::default()).boxed()
+    }
+
+    fn ingest_sst(&self, _: u64, req: IngestRequest) -> BoxFuture<'_, Result<IngestResponse>> {
+        let mut ssts = self.ingested_ssts.lock().unwrap();
+        ssts.push(req.get_sst().clone());
+        future::ok(IngestResponse::default()).boxed()
+    }
+
     fn has_region_id(&self, region_id: u64) -> BoxFuture<'_, Result<bool>> {
         let regions = self.regions.lock().unwrap();
         future::ok(regions.contains_key(&region_id)).boxed()
     }
 
-    fn is_scatter_region_finished(&self, _: u64) -> Result<bool> {
-        Ok(true)
+    fn is_scatter_region_finished(&self, _: u64) -> BoxFuture<'_, Result<bool>> {
+        future::ok(true).boxed()
     }
 
     fn is_space_enough(&self, _: u64, _: u64) -> BoxFuture<'_, Result<bool>> {
         future::ok(true).boxed()
     }
 }
+
+/// Creates a data key manager with a file master key, the key file and the
+/// key dictionary are placed in `dir`.
+pub fn new_key_manager(dir: &Path) -> Arc<DataKeyManager> {
+    let key_path = dir.join("master.key");
+    fs::write(
+        &key_path,
+        "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4\n",
+    )
+    .unwrap();
+    let cfg: SecurityConfig = toml::from_str(&format!(
+        r#"
+        [encryption]
+        data-encryption-method = "aes256-ctr"
+        [encryption.master-key]
+        type = "file"
+        path = "{}"
+        "#,
+        key_path.display()
+    ))
+    .unwrap();
+    let key_manager = data_key_manager_from_config(&cfg.encryption, dir.to_str().unwrap());
+    Arc::new(key_manager.unwrap().unwrap())
+}
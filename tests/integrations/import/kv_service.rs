@@ -15,9 +15,12 @@ use kvproto::import_kvpb::*;
 
 use security::SecurityManager;
 use test_util::{new_security_cfg, retry};
+use tikv_importer::import::import_kv_extpb::{self as extpb, ImportKvExtClient};
 use tikv_importer::import::{ImportKVServer, TiKvConfig};
 
-fn new_kv_server(enable_client_tls: bool) -> (ImportKVServer, ImportKvClient, TempDir) {
+fn new_kv_server(
+    enable_client_tls: bool,
+) -> (ImportKVServer, ImportKvClient, ImportKvExtClient, TempDir) {
     let temp_dir = TempDir::new("test_import_kv_server").unwrap();
 
     let mut cfg = TiKvConfig::default();
@@ -39,16 +42,17 @@ fn new_kv_server(enable_client_tls: bool) -> (ImportKVServer, ImportKvClient, Te
             builder.connect(&addr)
         }
     };
-    let client = ImportKvClient::new(ch);
+    let client = ImportKvClient::new(ch.clone());
+    let ext_client = ImportKvExtClient::new(ch);
 
     // Return temp_dir as well, so that temp dir will be properly
     // deleted when it is dropped.
-    (server, client, temp_dir)
+    (server, client, ext_client, temp_dir)
 }
 
 #[test]
 fn test_kv_service_without_tls() {
-    let (mut server, client, _) = new_kv_server(false);
+    let (mut server, client, _, _) = new_kv_server(false);
     server.start();
 
     match retry!(client.get_version(&GetVersionRequest::default())) {
@@ -59,7 +63,7 @@ fn test_kv_service_without_tls() {
 
 #[test]
 fn test_kv_service() {
-    let (mut server, client, _) = new_kv_server(true);
+    let (mut server, client, ext_client, _) = new_kv_server(true);
     server.start();
 
     let resp = retry!(client.get_version(&GetVersionRequest::default())).unwrap();
@@ -112,8 +116,29 @@ fn test_kv_service() {
     let resp = retry!(client.write_engine_v3(&write)).unwrap();
     assert!(!resp.has_error());
 
+    // Delete the key written above, and put an empty value.
+    let mut write_mutations = extpb::WriteEngineMutationsRequest {
+        uuid: uuid.clone(),
+        commit_ts: 124,
+        mutations: vec![
+            extpb::Mutation {
+                op: extpb::MutationOp::Delete as i32,
+                key: vec![123],
+                value: vec![],
+            },
+            extpb::Mutation {
+                op: extpb::MutationOp::Put as i32,
+                key: vec![124],
+                value: vec![],
+            },
+        ],
+    };
+    retry!(ext_client.write_engine_mutations(&write_mutations)).unwrap();
+    write_mutations.mutations[0].op = 100;
+    assert!(ext_client.write_engine_mutations(&write_mutations).is_err());
+
     let mut head = WriteHead::default();
-    head.set_uuid(uuid);
+    head.set_uuid(uuid.clone());
 
     let mut m = Mutation::default();
     m.set_op(MutationOp::Put);
@@ -141,6 +166,47 @@ fn test_kv_service() {
     let resp = retry!(client.close_engine(&close)).unwrap();
     assert!(!resp.has_error());
 
+    // An asynchronous import fails as well if the job can not be started.
+    let import = extpb::ImportEngineExtRequest {
+        uuid: uuid.clone(),
+        pd_addr: String::new(),
+        async_import: true,
+    };
+    assert!(ext_client.import_engine_ext(&import).is_err());
+
+    // The engine has never been imported.
+    let status = extpb::ImportStatusRequest { uuid: uuid.clone() };
+    assert!(ext_client.import_status(&status).is_err());
+
+    // The engine is not importing.
+    let abort = extpb::AbortImportEngineRequest { uuid: uuid.clone() };
+    assert!(ext_client.abort_import_engine(&abort).is_err());
+
+    // Duplicate keys are not detected by default.
+    let dups = extpb::DuplicateKeysRequest { uuid: uuid.clone() };
+    let resp = retry!(ext_client.duplicate_keys(&dups)).unwrap();
+    assert!(resp.keys.is_empty());
+
+    // All versions and deletions are counted.
+    let stats = extpb::EngineStatsRequest { uuid: uuid.clone() };
+    let resp = retry!(ext_client.engine_stats(&stats)).unwrap();
+    assert_eq!(resp.kvs, 5);
+    assert_eq!(resp.deleted_kvs, 1);
+    assert_eq!(resp.commit_ts, vec![123, 124]);
+
+    // The deleted key is not counted.
+    let checksum = extpb::ChecksumEngineRequest {
+        uuid: uuid.clone(),
+        per_table: true,
+    };
+    let resp = retry!(ext_client.checksum_engine(&checksum)).unwrap();
+    assert_eq!(resp.total.unwrap().total_kvs, 3);
+    assert!(resp.tables.is_empty());
+
+    // The engine will be imported from scratch.
+    let reset = extpb::ResetImportCheckpointRequest { uuid };
+    retry!(ext_client.reset_import_checkpoint(&reset)).unwrap();
+
     server.shutdown();
 }
 